@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::signing::constant_time_eq;
+
+/// API key with its limits. The name identifies the owner of the jobs and is never secret.
+#[derive(Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    pub key: String,
+    /// Requests per minute.
+    pub rate_limit: Option<u32>,
+    /// Max size of the uploaded and downloaded inputs in bytes.
+    pub max_upload_size: Option<usize>,
+    /// Codecs which the key may transcode to, any codec if not set.
+    pub allowed_codecs: Option<Vec<String>>,
+}
+
+impl ApiKey {
+    /// Requests per minute, `None` if the key is not limited. Zero means no limit too.
+    pub fn rate_limit(&self) -> Option<u32> {
+        self.rate_limit.filter(|&limit| limit > 0)
+    }
+
+    pub fn allows_codec(&self, codec: &str) -> bool {
+        match &self.allowed_codecs {
+            Some(codecs) => codecs.iter().any(|allowed| allowed == codec.trim()),
+            None => true,
+        }
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("name", &self.name)
+            .field("key", &"<redacted>")
+            .field("rate_limit", &self.rate_limit)
+            .field("max_upload_size", &self.max_upload_size)
+            .field("allowed_codecs", &self.allowed_codecs)
+            .finish()
+    }
+}
+
+/// Authenticated caller, added to the request extensions by the middleware.
+#[derive(Clone)]
+pub struct Caller(pub Arc<ApiKey>);
+
+/// Token bucket which is refilled continuously with `rate_limit` tokens per minute.
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Configured API keys. Authentication is disabled if there are no keys.
+pub struct ApiKeys {
+    keys: Vec<Arc<ApiKey>>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl ApiKeys {
+    /// Loads the keys from the JSON file, or from the JSON in `keys` if the file is not set.
+    pub(crate) fn load(file: Option<&str>, keys: Option<&str>) -> Result<Self, String> {
+        let json = match (file, keys) {
+            (Some(path), _) => fs::read_to_string(path)
+                .map_err(|err| format!("couldn't read API keys file {}: {}", path, err))?,
+            (None, Some(keys)) => keys.to_string(),
+            (None, None) => "[]".to_string(),
+        };
+        let keys: Vec<ApiKey> =
+            serde_json::from_str(&json).map_err(|err| format!("invalid API keys: {}", err))?;
+
+        let mut names = Vec::new();
+        for key in &keys {
+            if key.name.is_empty() || key.key.is_empty() {
+                return Err("API key name and key must not be empty".to_string());
+            }
+            if names.contains(&key.name.as_str()) {
+                return Err(format!("duplicate API key name: {}", key.name));
+            }
+            names.push(key.name.as_str());
+        }
+
+        Ok(ApiKeys {
+            keys: keys.into_iter().map(Arc::new).collect(),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Finds the key, every key is compared in constant time.
+    pub fn find(&self, provided: &str) -> Option<Arc<ApiKey>> {
+        self.keys.iter().fold(None, |found, key| {
+            if constant_time_eq(key.key.as_bytes(), provided.as_bytes()) {
+                Some(Arc::clone(key))
+            } else {
+                found
+            }
+        })
+    }
+
+    /// Takes `tokens` from the key's bucket. Returns how long to wait if the limit is exceeded.
+    pub fn acquire(&self, key: &ApiKey, tokens: u32) -> Result<(), Duration> {
+        let Some(rate_limit) = key.rate_limit() else {
+            return Ok(());
+        };
+        if tokens == 0 {
+            return Ok(());
+        }
+        let capacity = f64::from(rate_limit);
+        let per_second = capacity / 60.0;
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.name.clone()).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated_at = now;
+
+        let tokens = f64::from(tokens);
+        if bucket.tokens >= tokens {
+            bucket.tokens -= tokens;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (tokens - bucket.tokens) / per_second,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> ApiKeys {
+        ApiKeys::load(
+            None,
+            Some(
+                r#"[
+                    {"name": "billing", "key": "billing-secret", "rate_limit": 2},
+                    {"name": "search", "key": "search-secret", "rate_limit": 0}
+                ]"#,
+            ),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn find_matches_the_whole_key() {
+        let keys = keys();
+        assert_eq!(keys.find("billing-secret").unwrap().name, "billing");
+        assert_eq!(keys.find("search-secret").unwrap().name, "search");
+        assert!(keys.find("billing").is_none());
+        assert!(keys.find("billing-secret ").is_none());
+        assert!(keys.find("").is_none());
+    }
+
+    #[test]
+    fn acquire_limits_requests() {
+        let keys = keys();
+        let key = keys.find("billing-secret").unwrap();
+        assert!(keys.acquire(&key, 1).is_ok());
+        assert!(keys.acquire(&key, 1).is_ok());
+        let wait = keys.acquire(&key, 1).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(30));
+        assert!(keys.acquire(&key, 0).is_ok());
+    }
+
+    #[test]
+    fn acquire_charges_tokens() {
+        let keys = keys();
+        let key = keys.find("billing-secret").unwrap();
+        assert!(keys.acquire(&key, 3).is_err());
+        assert!(keys.acquire(&key, 2).is_ok());
+        assert!(keys.acquire(&key, 1).is_err());
+    }
+
+    #[test]
+    fn zero_rate_limit_is_unlimited() {
+        let keys = keys();
+        let key = keys.find("search-secret").unwrap();
+        assert_eq!(key.rate_limit(), None);
+        for _ in 0..100 {
+            assert!(keys.acquire(&key, 10).is_ok());
+        }
+    }
+
+    #[test]
+    fn load_rejects_invalid_keys() {
+        assert!(ApiKeys::load(None, Some(r#"[{"name": "", "key": "secret"}]"#)).is_err());
+        assert!(ApiKeys::load(
+            None,
+            Some(r#"[{"name": "a", "key": "one"}, {"name": "a", "key": "two"}]"#)
+        )
+        .is_err());
+        assert!(!ApiKeys::load(None, None).unwrap().is_enabled());
+    }
+
+    #[test]
+    fn debug_redacts_key() {
+        let keys = keys();
+        let debug = format!("{:?}", keys.find("billing-secret").unwrap());
+        assert!(debug.contains("billing"));
+        assert!(!debug.contains("billing-secret"));
+    }
+}
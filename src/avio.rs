@@ -0,0 +1,119 @@
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+
+use ffmpeg_next::format::context::Input;
+use ffmpeg_next::{ffi, Error};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// FFmpeg input context which reads the data from `R` through a custom AVIO read callback
+/// instead of opening a file. The input is not seekable, so formats which need seeking
+/// (e.g. MP4 with the index at the end) can't be read this way.
+///
+/// Read errors end the input as if it was complete, the reader is expected to keep the
+/// error so it can be reported once FFmpeg stops.
+pub struct StreamInput<'a, R: Read> {
+    input: Option<Input>,
+    avio: *mut ffi::AVIOContext,
+    _reader: PhantomData<&'a mut R>,
+}
+
+impl<'a, R: Read> StreamInput<'a, R> {
+    pub fn open(reader: &'a mut R) -> Result<Self, Error> {
+        let mut this = StreamInput {
+            input: None,
+            avio: ptr::null_mut(),
+            _reader: PhantomData,
+        };
+
+        unsafe {
+            let buffer = ffi::av_malloc(BUFFER_SIZE) as *mut u8;
+            if buffer.is_null() {
+                return Err(Error::Unknown);
+            }
+
+            this.avio = ffi::avio_alloc_context(
+                buffer,
+                BUFFER_SIZE as c_int,
+                0,
+                reader as *mut R as *mut c_void,
+                Some(read_packet::<R>),
+                None,
+                None,
+            );
+            if this.avio.is_null() {
+                ffi::av_free(buffer as *mut c_void);
+                return Err(Error::Unknown);
+            }
+
+            let mut ctx = ffi::avformat_alloc_context();
+            if ctx.is_null() {
+                return Err(Error::Unknown);
+            }
+            (*ctx).pb = this.avio;
+            (*ctx).flags |= ffi::AVFMT_FLAG_CUSTOM_IO as c_int;
+
+            // The context is freed by FFmpeg if it can't be opened.
+            let res = ffi::avformat_open_input(&mut ctx, ptr::null(), ptr::null(), ptr::null_mut());
+            if res < 0 {
+                return Err(Error::from(res));
+            }
+            this.input = Some(Input::wrap(ctx));
+
+            let res = ffi::avformat_find_stream_info(ctx, ptr::null_mut());
+            if res < 0 {
+                return Err(Error::from(res));
+            }
+        }
+
+        Ok(this)
+    }
+}
+
+impl<'a, R: Read> Deref for StreamInput<'a, R> {
+    type Target = Input;
+
+    fn deref(&self) -> &Input {
+        self.input.as_ref().expect("input is opened")
+    }
+}
+
+impl<'a, R: Read> DerefMut for StreamInput<'a, R> {
+    fn deref_mut(&mut self) -> &mut Input {
+        self.input.as_mut().expect("input is opened")
+    }
+}
+
+impl<'a, R: Read> Drop for StreamInput<'a, R> {
+    fn drop(&mut self) {
+        // Closing the input doesn't free the custom IO context and its buffer.
+        self.input = None;
+        if !self.avio.is_null() {
+            unsafe {
+                ffi::av_freep(&mut (*self.avio).buffer as *mut *mut u8 as *mut c_void);
+                ffi::avio_context_free(&mut self.avio);
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn read_packet<R: Read>(
+    opaque: *mut c_void,
+    buf: *mut u8,
+    buf_size: c_int,
+) -> c_int {
+    let reader = &mut *(opaque as *mut R);
+    let buf = slice::from_raw_parts_mut(buf, buf_size.max(0) as usize);
+    loop {
+        match reader.read(buf) {
+            Ok(0) => return ffi::AVERROR_EOF,
+            Ok(n) => return n as c_int,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return ffi::AVERROR_EOF,
+        }
+    }
+}
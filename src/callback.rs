@@ -0,0 +1,458 @@
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::{debug, error, info};
+use ureq::{Agent, AgentBuilder};
+use uuid::Uuid;
+
+use crate::dead_letter::{DeadLetter, DeadLetterStore};
+use crate::dto::{CallbackDelivery, ConvertBatchResponse, ConvertResponse, Headers};
+use crate::fetcher::DownloadOptions;
+use crate::registry::{CompletedBatch, JobError};
+use crate::signing::{hmac_sha256_hex, LinkSigner};
+use crate::task::JobResult;
+
+pub const TIMESTAMP_HEADER: &str = "X-Atranscoder-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Atranscoder-Signature";
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Where and how the callback is delivered.
+#[derive(Clone, Debug)]
+pub struct CallbackTarget {
+    pub url: String,
+    pub method: &'static str,
+    pub headers: Headers,
+}
+
+impl CallbackTarget {
+    /// Builds the target from the request fields. Returns `None` if the URL is missing or empty,
+    /// the method defaults to POST.
+    pub fn from_request(
+        url: Option<String>,
+        method: Option<&str>,
+        headers: Option<Headers>,
+    ) -> Result<Option<Self>, String> {
+        let Some(url) = url.filter(|url| !url.is_empty()) else {
+            return Ok(None);
+        };
+
+        let method = match method.map(str::to_uppercase).as_deref() {
+            None | Some("") | Some("POST") => "POST",
+            Some("PUT") => "PUT",
+            Some(other) => return Err(format!("unsupported callback method: {}", other)),
+        };
+
+        let headers = headers.unwrap_or_default();
+        headers.validate()?;
+
+        Ok(Some(CallbackTarget {
+            url,
+            method,
+            headers,
+        }))
+    }
+}
+
+/// Sends job results to the callback URLs. If the secret is configured, every callback is
+/// signed with HMAC-SHA256 over `<timestamp>.<body>`, the timestamp and the signature are
+/// sent in the `X-Atranscoder-Timestamp` and `X-Atranscoder-Signature` headers.
+///
+/// Any 2xx response is a success. Network errors, 429 and 5xx responses are retried up to
+/// `max_attempts` times with exponential backoff, all attempts together take at most
+/// `delivery_timeout`. Callbacks which couldn't be delivered are kept in the dead-letter store,
+/// if it's configured, to be replayed later.
+pub struct Notifier {
+    agent: Agent,
+    secret: Option<String>,
+    public_url: String,
+    max_attempts: u32,
+    /// Limit for a single attempt, the whole request and response.
+    attempt_timeout: Duration,
+    delivery_timeout: Duration,
+    dead_letters: Option<DeadLetterStore>,
+    links: Option<LinkSigner>,
+}
+
+impl Notifier {
+    /// `public_url` is the address of this service as seen by the callback receivers,
+    /// it is used to build the result download URLs.
+    pub(crate) fn new(
+        secret: Option<String>,
+        public_url: Option<String>,
+        max_attempts: u32,
+        delivery_timeout: Duration,
+        options: &DownloadOptions,
+    ) -> Self {
+        // Reads and writes are limited by the timeout of every attempt
+        let agent = AgentBuilder::new()
+            .timeout_connect(options.connect_timeout)
+            .build();
+
+        Notifier {
+            agent,
+            secret: secret.filter(|secret| !secret.is_empty()),
+            public_url: public_url
+                .unwrap_or_default()
+                .trim_end_matches('/')
+                .to_string(),
+            max_attempts: max_attempts.max(1),
+            attempt_timeout: options.read_timeout,
+            delivery_timeout,
+            dead_letters: None,
+            links: None,
+        }
+    }
+
+    pub(crate) fn with_dead_letters(mut self, store: DeadLetterStore) -> Self {
+        self.dead_letters = Some(store);
+        self
+    }
+
+    pub fn dead_letters(&self) -> Option<&DeadLetterStore> {
+        self.dead_letters.as_ref()
+    }
+
+    /// Makes the download URLs in the callbacks signed.
+    pub(crate) fn with_link_signer(mut self, signer: LinkSigner) -> Self {
+        self.links = Some(signer);
+        self
+    }
+
+    pub fn link_signer(&self) -> Option<&LinkSigner> {
+        self.links.as_ref()
+    }
+
+    /// Links are signed to be valid as long as the result is kept.
+    fn download_url(&self, id: Uuid, result_ttl: Duration) -> String {
+        match &self.links {
+            Some(links) => format!(
+                "{}/get/{}?{}",
+                self.public_url,
+                id,
+                links.sign("GET", id, result_ttl)
+            ),
+            None => format!("{}/get/{}", self.public_url, id),
+        }
+    }
+
+    pub fn send_error(
+        &self,
+        id: Uuid,
+        error: &JobError,
+        target: Option<&CallbackTarget>,
+    ) -> Option<CallbackDelivery> {
+        let target = target?;
+
+        Some(self.deliver(
+            id,
+            target,
+            &ConvertResponse {
+                id: Some(id.to_string()),
+                error: Some(error.message.clone()),
+                error_code: Some(error.code),
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// `result_ttl` is how long the result is still kept.
+    pub fn send_ok(
+        &self,
+        id: Uuid,
+        result: &JobResult,
+        result_ttl: Duration,
+        target: Option<&CallbackTarget>,
+    ) -> Option<CallbackDelivery> {
+        let target = target?;
+
+        Some(self.deliver(
+            id,
+            target,
+            &ConvertResponse {
+                id: Some(id.to_string()),
+                error: None,
+                url: Some(self.download_url(id, result_ttl)),
+                size: Some(result.size),
+                mime_type: Some(result.mime_type.clone()),
+                file_name: Some(result.file_name.clone()),
+                duration: Some(result.duration),
+                codec: Some(result.codec.clone()),
+                sample_rate: Some(result.sample_rate),
+                channels: Some(result.channels),
+                bit_rate: Some(result.bit_rate),
+                processing_time_ms: Some(result.processing_time_ms),
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// Sends the batch completion callback. Errors are only logged because there's nobody
+    /// else to report them to.
+    pub fn send_batch(&self, batch: &CompletedBatch) {
+        let payload = ConvertBatchResponse {
+            batch_id: Some(batch.id.to_string()),
+            items: batch
+                .items
+                .iter()
+                .map(|item| ConvertResponse {
+                    id: Some(item.id.to_string()),
+                    error: item.error.as_ref().map(|error| error.message.clone()),
+                    error_code: item.error.as_ref().map(|error| error.code),
+                    url: item
+                        .error
+                        .is_none()
+                        .then(|| self.download_url(item.id, item.result_ttl)),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+
+        let delivery = self.deliver(batch.id, &batch.callback, &payload);
+        if delivery.delivered {
+            debug!(
+                "batch id={} result was sent to callback {}",
+                batch.id, batch.callback.url
+            );
+        } else {
+            error!(
+                "failed to send batch callback for batch id={} after {} attempts: {}",
+                batch.id,
+                delivery.attempts,
+                delivery.error.unwrap_or_default()
+            );
+        }
+    }
+
+    /// Sends the stored callback again. The callback is removed from the store once it's
+    /// delivered, otherwise its delivery outcome is updated.
+    pub fn replay(
+        &self,
+        mut letter: DeadLetter,
+    ) -> Result<CallbackDelivery, Box<dyn Error + Send + Sync>> {
+        let store = self
+            .dead_letters
+            .as_ref()
+            .ok_or("dead-letter store is not configured")?;
+        let target = CallbackTarget::from_request(
+            Some(letter.url.clone()),
+            Some(&letter.method),
+            Some(letter.headers.clone()),
+        )?
+        .ok_or("callback URL is empty")?;
+
+        let delivery = self.deliver_body(&target, &letter.body);
+        if delivery.delivered {
+            store.remove(letter.id)?;
+            info!(
+                "dead letter id={} was delivered to callback {}",
+                letter.id, target.url
+            );
+        } else {
+            letter.attempts += delivery.attempts;
+            letter.error.clone_from(&delivery.error);
+            letter.failed_at = unix_time();
+            store.save(&letter)?;
+        }
+        Ok(delivery)
+    }
+
+    /// Delivers the payload and keeps it in the dead-letter store if it couldn't be delivered.
+    /// `subject` is the ID of the job or the batch.
+    fn deliver<T: Serialize>(
+        &self,
+        subject: Uuid,
+        target: &CallbackTarget,
+        payload: &T,
+    ) -> CallbackDelivery {
+        let body = match serde_json::to_string(payload) {
+            Ok(body) => body,
+            Err(err) => {
+                return CallbackDelivery {
+                    error: Some(format!("couldn't serialize callback: {}", err)),
+                    ..Default::default()
+                }
+            }
+        };
+
+        let delivery = self.deliver_body(target, &body);
+        if !delivery.delivered {
+            if let Some(store) = &self.dead_letters {
+                let letter = DeadLetter {
+                    id: Uuid::new_v4(),
+                    subject,
+                    url: target.url.clone(),
+                    method: target.method.to_string(),
+                    headers: target.headers.clone(),
+                    body,
+                    attempts: delivery.attempts,
+                    error: delivery.error.clone(),
+                    failed_at: unix_time(),
+                };
+                match store.save(&letter) {
+                    Ok(()) => info!(
+                        "callback for id={} was saved as dead letter id={}",
+                        subject, letter.id
+                    ),
+                    Err(err) => error!("couldn't save dead letter for id={}: {}", subject, err),
+                }
+            }
+        }
+        delivery
+    }
+
+    /// Delivers the body, retrying if the receiver is temporarily unavailable. Gives up when
+    /// the delivery timeout is over.
+    fn deliver_body(&self, target: &CallbackTarget, body: &str) -> CallbackDelivery {
+        let mut delivery = CallbackDelivery::default();
+        let mut delay = RETRY_DELAY;
+        let deadline = Instant::now() + self.delivery_timeout;
+        loop {
+            delivery.attempts += 1;
+            let started_at = Instant::now();
+            let timeout = deadline
+                .saturating_duration_since(started_at)
+                .min(self.attempt_timeout);
+            let result = self.send(target, body, timeout);
+            delivery.latency_ms = started_at.elapsed().as_millis() as u64;
+
+            let retryable = match result {
+                Ok(status) => {
+                    delivery.status = Some(status);
+                    delivery.delivered = (200..300).contains(&status);
+                    delivery.error = (!delivery.delivered).then(|| {
+                        format!(
+                            "failed to send callback to {}. Status: {}",
+                            target.url, status
+                        )
+                    });
+                    status == 429 || status >= 500
+                }
+                Err(err) => {
+                    delivery.status = None;
+                    delivery.error = Some(err.to_string());
+                    true
+                }
+            };
+
+            if delivery.delivered
+                || !retryable
+                || delivery.attempts >= self.max_attempts
+                || Instant::now() + delay >= deadline
+            {
+                return delivery;
+            }
+
+            debug!(
+                "callback to {} failed (attempt {}), retrying in {:?}",
+                target.url, delivery.attempts, delay
+            );
+            thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+
+    /// Sends a single callback request and returns the response status.
+    fn send(
+        &self,
+        target: &CallbackTarget,
+        body: &str,
+        timeout: Duration,
+    ) -> Result<u16, Box<dyn Error>> {
+        let mut request = self
+            .agent
+            .request(target.method, &target.url)
+            .timeout(timeout);
+
+        // Custom headers go first so they can't override the content type and the signature.
+        for (name, value) in target.headers.iter() {
+            request = request.set(name, value);
+        }
+        request = request.set("Content-Type", "application/json");
+
+        if let Some(secret) = &self.secret {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let signature = hmac_sha256_hex(
+                secret.as_bytes(),
+                format!("{}.{}", timestamp, body).as_bytes(),
+            );
+            request = request
+                .set(TIMESTAMP_HEADER, &timestamp.to_string())
+                .set(SIGNATURE_HEADER, &format!("sha256={}", signature));
+        }
+
+        match request.send_string(body) {
+            Ok(response) => Ok(response.status()),
+            Err(ureq::Error::Status(status, _)) => Ok(status),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn options(read_timeout: Duration) -> DownloadOptions {
+        DownloadOptions {
+            connect_timeout: Duration::from_secs(1),
+            read_timeout,
+            max_redirects: 0,
+            max_resumes: 0,
+        }
+    }
+
+    fn target(listener: &TcpListener) -> CallbackTarget {
+        CallbackTarget {
+            url: format!("http://{}/callback", listener.local_addr().unwrap()),
+            method: "POST",
+            headers: Headers::default(),
+        }
+    }
+
+    #[test]
+    fn delivery_is_bounded_by_timeout() {
+        // Accepts the connections and never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let notifier = Notifier::new(
+            None,
+            None,
+            10,
+            Duration::from_millis(500),
+            &options(Duration::from_secs(30)),
+        );
+
+        let started_at = Instant::now();
+        let delivery = notifier.deliver_body(&target(&listener), "{}");
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        assert!(!delivery.delivered);
+        assert_eq!(delivery.attempts, 1);
+        assert!(delivery.error.is_some());
+    }
+
+    #[test]
+    fn stalled_receiver_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let notifier = Notifier::new(
+            None,
+            None,
+            1,
+            Duration::from_secs(30),
+            &options(Duration::from_millis(200)),
+        );
+
+        let started_at = Instant::now();
+        let delivery = notifier.deliver_body(&target(&listener), "{}");
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        assert!(!delivery.delivered);
+    }
+}
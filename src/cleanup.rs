@@ -0,0 +1,139 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::filepath;
+use crate::metrics::Metrics;
+use crate::task::TaskContext;
+
+/// Files removed by a cleanup run.
+#[derive(Default)]
+struct Cleaned {
+    files: u64,
+    bytes: u64,
+}
+
+impl Cleaned {
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Removes the files of the expired jobs. Files of the queued and running jobs are never
+/// touched, the files of the jobs unknown to the registry (e.g. left after a restart) are
+/// removed once they are older than `orphan_ttl`.
+pub struct Cleaner {
+    context: Arc<TaskContext>,
+    metrics: Arc<Metrics>,
+    work_dir: PathBuf,
+    orphan_ttl: Duration,
+}
+
+impl Cleaner {
+    pub(crate) fn new(
+        context: Arc<TaskContext>,
+        metrics: Arc<Metrics>,
+        work_dir: impl Into<PathBuf>,
+        orphan_ttl: Duration,
+    ) -> Self {
+        Cleaner {
+            context,
+            metrics,
+            work_dir: work_dir.into(),
+            orphan_ttl,
+        }
+    }
+
+    pub fn run(&self) {
+        let mut cleaned = Cleaned::default();
+        for id in self.context.registry.remove_expired() {
+            self.remove_job_files(id, &mut cleaned);
+        }
+        if let Err(err) = self.remove_orphans(&mut cleaned) {
+            error!("could not perform working directory cleanup: {}", err);
+        }
+
+        if cleaned.files > 0 {
+            info!(
+                "cleanup removed {} files, freed {} bytes",
+                cleaned.files, cleaned.bytes
+            );
+        }
+        self.metrics.record_cleanup(cleaned.files, cleaned.bytes);
+    }
+
+    fn remove_job_files(&self, id: Uuid, cleaned: &mut Cleaned) {
+        let id = id.to_string();
+        let input = self.work_dir.join(filepath::in_file_name(&id));
+        let output = self.work_dir.join(filepath::out_file_name(&id));
+
+        // Inputs are removed after transcoding, this is only what's left after errors.
+        if let Some(size) = remove_file(&input) {
+            cleaned.add(size);
+        }
+
+        // The local storage keeps the result in the working directory, so it's removed here
+        // to be measured. Other storages are only asked to delete their copy.
+        if let Some(size) = remove_file(&output) {
+            cleaned.add(size);
+        }
+        let key = filepath::out_file_name(&id);
+        if let Err(err) = self.context.storage.delete(&key) {
+            error!("couldn't remove the result {}: {}", key, err);
+        }
+    }
+
+    fn remove_orphans(&self, cleaned: &mut Cleaned) -> io::Result<()> {
+        let now = SystemTime::now();
+        for entry in fs::read_dir(&self.work_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(OsStr::to_str) != Some(filepath::EXT) {
+                continue;
+            }
+
+            let job_id = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|name| name.split('.').next())
+                .and_then(|id| id.parse::<Uuid>().ok());
+            if job_id.is_some_and(|id| self.context.registry.contains(id)) {
+                continue;
+            }
+
+            let expired = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| {
+                    now.duration_since(modified)
+                        .is_ok_and(|age| age > self.orphan_ttl)
+                });
+            if expired {
+                if let Some(size) = remove_file(&path) {
+                    cleaned.add(size);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes the file and returns its size, `None` if it doesn't exist or can't be removed.
+fn remove_file(path: &Path) -> Option<u64> {
+    let size = fs::metadata(path).ok()?.len();
+    match fs::remove_file(path) {
+        Ok(()) => {
+            debug!("removed file: {:?}", path);
+            Some(size)
+        }
+        Err(err) => {
+            error!("couldn't remove {:?}: {}", path, err);
+            None
+        }
+    }
+}
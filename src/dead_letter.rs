@@ -0,0 +1,125 @@
+use std::fs::{self, DirBuilder, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::dto::Headers;
+
+/// Callback which couldn't be delivered after all the attempts.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeadLetter {
+    pub id: Uuid,
+    /// ID of the job or the batch the callback belongs to.
+    pub subject: Uuid,
+    pub url: String,
+    pub method: String,
+    pub headers: Headers,
+    pub body: String,
+    pub attempts: u32,
+    pub error: Option<String>,
+    /// Unix timestamp of the last failed delivery.
+    pub failed_at: u64,
+}
+
+/// Keeps undeliverable callbacks on disk as JSON files, one file per callback,
+/// so they survive restarts and can be replayed later. The callbacks contain the headers and
+/// the signed download links, so only the owner of the process may read them.
+pub struct DeadLetterStore {
+    dir: PathBuf,
+}
+
+impl DeadLetterStore {
+    pub(crate) fn new(dir: PathBuf) -> io::Result<Self> {
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        // The directory may be left by an older version with the default mode
+        fs::set_permissions(&dir, Permissions::from_mode(0o700))?;
+        Ok(DeadLetterStore { dir })
+    }
+
+    pub fn save(&self, letter: &DeadLetter) -> io::Result<()> {
+        let path = self.path(letter.id);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(letter)?)?;
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn get(&self, id: Uuid) -> io::Result<Option<DeadLetter>> {
+        match fs::read(self.path(id)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns all the stored callbacks, the oldest first.
+    pub fn list(&self) -> io::Result<Vec<DeadLetter>> {
+        let mut letters = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            // Skip the files which were removed or replaced in the meantime.
+            if let Ok(data) = fs::read(&path) {
+                if let Ok(letter) = serde_json::from_slice::<DeadLetter>(&data) {
+                    letters.push(letter);
+                }
+            }
+        }
+        letters.sort_by_key(|letter| letter.failed_at);
+        Ok(letters)
+    }
+
+    pub fn remove(&self, id: Uuid) -> io::Result<()> {
+        match fs::remove_file(self.path(id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_is_private() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("dead_letters");
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+
+        let store = DeadLetterStore::new(dir.clone()).unwrap();
+        let letter = DeadLetter {
+            id: Uuid::new_v4(),
+            subject: Uuid::new_v4(),
+            url: "https://example.com/callback".to_string(),
+            method: "POST".to_string(),
+            headers: Headers::default(),
+            body: "{}".to_string(),
+            attempts: 3,
+            error: None,
+            failed_at: 0,
+        };
+        store.save(&letter).unwrap();
+
+        let mode =
+            |path: &std::path::Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&store.path(letter.id)), 0o600);
+        assert!(store.get(letter.id).unwrap().is_some());
+    }
+}
@@ -0,0 +1,144 @@
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use axum::http::StatusCode;
+
+use crate::dto::{ErrorCode, UsageResponse};
+use crate::filepath;
+
+/// Why a new job can't be accepted.
+#[derive(Debug)]
+pub enum DiskError {
+    /// The `.atranscoder` files would take more than the quota.
+    QuotaExceeded { used: u64, quota: u64 },
+    /// The volume has less free space than the threshold.
+    LowFreeSpace { free: u64, min_free: u64 },
+}
+
+impl DiskError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            DiskError::QuotaExceeded { .. } => ErrorCode::DiskQuotaExceeded,
+            DiskError::LowFreeSpace { .. } => ErrorCode::LowDiskSpace,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            DiskError::QuotaExceeded { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            DiskError::LowFreeSpace { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskError::QuotaExceeded { used, quota } => write!(
+                f,
+                "Disk quota exceeded: {} of {} bytes are used",
+                used, quota
+            ),
+            DiskError::LowFreeSpace { free, min_free } => write!(
+                f,
+                "Not enough free disk space: {} bytes are free, {} bytes are required",
+                free, min_free
+            ),
+        }
+    }
+}
+
+/// Keeps the working directory within the quota and the free space above the threshold.
+/// Only the new jobs are rejected, the running ones may still go over the limits.
+pub struct DiskGuard {
+    dir: PathBuf,
+    quota: Option<u64>,
+    min_free: Option<u64>,
+}
+
+impl DiskGuard {
+    pub(crate) fn new(dir: impl Into<PathBuf>, quota: Option<u64>, min_free: Option<u64>) -> Self {
+        DiskGuard {
+            dir: dir.into(),
+            quota: quota.filter(|&quota| quota > 0),
+            min_free: min_free.filter(|&min_free| min_free > 0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.quota.is_some() || self.min_free.is_some()
+    }
+
+    /// Checks whether a job with `size` bytes of the input can be accepted.
+    pub fn check(&self, size: u64) -> io::Result<Result<(), DiskError>> {
+        if !self.is_enabled() {
+            return Ok(Ok(()));
+        }
+
+        let usage = self.usage()?;
+        if let Some(quota) = self.quota {
+            if usage.used_bytes + size > quota {
+                return Ok(Err(DiskError::QuotaExceeded {
+                    used: usage.used_bytes,
+                    quota,
+                }));
+            }
+        }
+        if let Some(min_free) = self.min_free {
+            if usage.free_bytes < min_free + size {
+                return Ok(Err(DiskError::LowFreeSpace {
+                    free: usage.free_bytes,
+                    min_free,
+                }));
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    /// Sums up the `.atranscoder` files in the working directory.
+    pub fn usage(&self) -> io::Result<UsageResponse> {
+        let mut files = 0;
+        let mut used_bytes = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(OsStr::to_str) != Some(filepath::EXT) {
+                continue;
+            }
+            // Files may be removed while the directory is being read.
+            match entry.metadata() {
+                Ok(metadata) if metadata.is_file() => {
+                    files += 1;
+                    used_bytes += metadata.len();
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(UsageResponse {
+            files,
+            used_bytes,
+            quota_bytes: self.quota,
+            free_bytes: free_space(&self.dir)?,
+            min_free_bytes: self.min_free,
+        })
+    }
+}
+
+/// Space available to unprivileged users on the volume of the path.
+fn free_space(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
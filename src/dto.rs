@@ -1,11 +1,127 @@
+use std::collections::HashMap;
+use std::fmt;
+
 use axum_typed_multipart::{FieldData, TryFromMultipart};
 use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 
-#[derive(Serialize, Deserialize)]
+use crate::registry::JobStatus;
+
+const REDACTED: &str = "[REDACTED]";
+const SENSITIVE_HEADER_PARTS: [&str; 7] = [
+    "auth", "cookie", "token", "secret", "key", "password", "session",
+];
+/// Hop-by-hop and framing headers, they are set by the HTTP client.
+const RESERVED_HEADERS: [&str; 10] = [
+    "connection",
+    "content-length",
+    "expect",
+    "host",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+/// Set by the downloader to resume interrupted downloads.
+const DOWNLOAD_RESERVED_HEADERS: [&str; 2] = ["range", "if-range"];
+
+#[derive(Serialize, Deserialize, Default)]
 pub struct ConvertResponse {
     pub id: Option<String>,
     pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_rate: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processing_time_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    DownloadFailed,
+    TranscodingFailed,
+    WorkerCrashed,
+    ShuttingDown,
+    UploadFailed,
+    ChecksumMismatch,
+    InputTooLarge,
+    UnsupportedMediaType,
+    StorageFailed,
+    DiskQuotaExceeded,
+    LowDiskSpace,
+}
+
+/// Outcome of the last callback delivery for the job.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CallbackDelivery {
+    pub delivered: bool,
+    pub status: Option<u16>,
+    pub attempts: u32,
+    /// Latency of the last attempt.
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct JobStatusResponse {
+    pub id: String,
+    pub status: JobStatus,
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+    pub callback: Option<CallbackDelivery>,
+}
+
+/// Query of the signed download links.
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    pub expires: Option<u64>,
+    pub signature: Option<String>,
+}
+
+/// Disk usage of the working directory.
+#[derive(Serialize)]
+pub struct UsageResponse {
+    /// Number of the input and result files.
+    pub files: u64,
+    pub used_bytes: u64,
+    pub quota_bytes: Option<u64>,
+    pub free_bytes: u64,
+    pub min_free_bytes: Option<u64>,
+}
+
+/// Undeliverable callback as shown to the admins, sensitive header values are redacted.
+#[derive(Serialize)]
+pub struct DeadLetterResponse {
+    pub id: String,
+    pub subject: String,
+    pub url: String,
+    pub method: String,
+    pub headers: Headers,
+    pub body: String,
+    pub attempts: u32,
+    pub error: Option<String>,
+    pub failed_at: u64,
 }
 
 #[derive(TryFromMultipart)]
@@ -18,6 +134,17 @@ pub struct ConvertRequest {
     pub sample_rate: i32,
     pub channel_layout: Option<String>,
     pub callback_url: Option<String>,
+    pub callback_method: Option<String>,
+    /// JSON object with the callback headers.
+    pub callback_headers: Option<String>,
+    pub output_url: Option<String>,
+    pub no_dedup: Option<bool>,
+    /// How long the result is kept, in seconds.
+    pub result_ttl: Option<u64>,
+    /// Remove the result after the first successful download.
+    pub delete_after_download: Option<bool>,
+    /// Name of the result file, the input file name with the output extension by default.
+    pub file_name: Option<String>,
 
     #[form_data(limit = "1GiB")]
     pub file: FieldData<NamedTempFile>,
@@ -33,10 +160,176 @@ pub struct ConvertURLRequest {
     pub sample_rate: i32,
     pub channel_layout: Option<String>,
     pub url: String,
+    /// Headers sent with the input download request.
+    pub headers: Option<Headers>,
+    /// Expected SHA-256 of the input, `sha256:<hex>` or just `<hex>`.
+    pub checksum: Option<String>,
+    /// Transcode the input while it's being downloaded.
+    pub stream: Option<bool>,
     pub callback_url: Option<String>,
+    pub callback_method: Option<String>,
+    pub callback_headers: Option<Headers>,
+    pub output_url: Option<String>,
+    pub no_dedup: Option<bool>,
+    /// How long the result is kept, in seconds.
+    pub result_ttl: Option<u64>,
+    /// Remove the result after the first successful download.
+    pub delete_after_download: Option<bool>,
+    /// Name of the result file, the input file name with the output extension by default.
+    pub file_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConvertBatchRequest {
+    pub items: Vec<ConvertURLRequest>,
+    pub callback_url: Option<String>,
+    pub callback_method: Option<String>,
+    pub callback_headers: Option<Headers>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConvertBatchResponse {
+    pub batch_id: Option<String>,
+    pub items: Vec<ConvertResponse>,
 }
 
 #[derive(Serialize)]
 pub(crate) struct ErrorResponse {
-    pub(crate) error: String
-}
\ No newline at end of file
+    pub(crate) error: String,
+}
+
+/// HTTP headers provided by the client. Values of the sensitive headers (authorization,
+/// cookies, tokens, etc.) are redacted in the `Debug` output so they never end up in the logs.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct Headers(pub HashMap<String, String>);
+
+impl Headers {
+    pub fn is_sensitive(name: &str) -> bool {
+        let name = name.to_lowercase();
+        SENSITIVE_HEADER_PARTS
+            .iter()
+            .any(|part| name.contains(part))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in &self.0 {
+            if axum::http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(format!("invalid header name: {}", name));
+            }
+            if axum::http::HeaderValue::from_str(value).is_err() {
+                return Err(format!("invalid value for header {}", name));
+            }
+            if RESERVED_HEADERS.contains(&name.to_lowercase().as_str()) {
+                return Err(format!("header {} can't be set", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates the headers of the input downloads, which can't set the range either.
+    pub fn validate_download(&self) -> Result<(), String> {
+        self.validate()?;
+        match self
+            .0
+            .keys()
+            .find(|name| DOWNLOAD_RESERVED_HEADERS.contains(&name.to_lowercase().as_str()))
+        {
+            Some(name) => Err(format!("header {} can't be set", name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a copy with the values of the sensitive headers replaced.
+    pub fn redacted(&self) -> Headers {
+        Headers(
+            self.0
+                .iter()
+                .map(|(name, value)| {
+                    let value = if Headers::is_sensitive(name) {
+                        REDACTED.to_string()
+                    } else {
+                        value.clone()
+                    };
+                    (name.clone(), value)
+                })
+                .collect(),
+        )
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter()
+    }
+
+    /// Returns the headers in a stable order with lowercase names.
+    pub fn canonical(&self) -> String {
+        let mut headers: Vec<String> = self
+            .0
+            .iter()
+            .map(|(name, value)| format!("{}:{}", name.to_lowercase(), value))
+            .collect();
+        headers.sort_unstable();
+        headers.join("\n")
+    }
+}
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, value)| {
+                if Headers::is_sensitive(name) {
+                    (name.as_str(), REDACTED)
+                } else {
+                    (name.as_str(), value.as_str())
+                }
+            }))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &str, value: &str) -> Headers {
+        Headers(HashMap::from([(name.to_string(), value.to_string())]))
+    }
+
+    #[test]
+    fn validate_headers() {
+        assert!(headers("Authorization", "Bearer token").validate().is_ok());
+        assert!(headers("X-Custom", "value").validate_download().is_ok());
+        assert!(headers("bad header", "value").validate().is_err());
+        assert!(headers("X-Custom", "line\nbreak").validate().is_err());
+    }
+
+    #[test]
+    fn reserved_headers_are_rejected() {
+        for name in [
+            "Content-Length",
+            "transfer-encoding",
+            "Host",
+            "Connection",
+            "TE",
+        ] {
+            assert!(headers(name, "1").validate().is_err(), "{}", name);
+            assert!(headers(name, "1").validate_download().is_err(), "{}", name);
+        }
+        for name in ["Range", "if-range"] {
+            assert!(headers(name, "bytes=0-").validate().is_ok(), "{}", name);
+            assert!(
+                headers(name, "bytes=0-").validate_download().is_err(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn sensitive_headers_are_redacted() {
+        let redacted = headers("X-Api-Key", "secret").redacted();
+        assert_eq!(redacted.0["X-Api-Key"], REDACTED);
+        let redacted = headers("Accept", "audio/*").redacted();
+        assert_eq!(redacted.0["Accept"], "audio/*");
+    }
+}
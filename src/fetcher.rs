@@ -0,0 +1,751 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use infer::MatcherType;
+use tokio::sync::Semaphore;
+use tracing::{debug, error, warn};
+use ureq::{Agent, AgentBuilder, Error as UreqError, Response};
+use url::Url;
+use uuid::Uuid;
+
+use crate::dto::{ErrorCode, Headers};
+use crate::registry::file_digest;
+use crate::storage::S3Config;
+use crate::task::Task;
+use crate::thread_pool::{ThreadPool, SHUTDOWN_MESSAGE};
+use crate::url_policy::UrlPolicy;
+
+/// How long the presigned URLs of `s3://` inputs are valid, resumed downloads reuse them.
+const S3_URL_TTL: Duration = Duration::from_secs(6 * 3600);
+/// How many first bytes of the input are used to detect its type.
+const SNIFF_LEN: usize = 512;
+const TEXT_CONTENT_TYPES: [&str; 4] = [
+    "application/json",
+    "application/xml",
+    "application/xhtml+xml",
+    "application/problem+json",
+];
+
+/// HTTP client settings for the input downloads.
+#[derive(Clone, Copy)]
+pub struct DownloadOptions {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub max_redirects: u32,
+    /// How many times an interrupted download is resumed.
+    pub max_resumes: u32,
+}
+
+/// Downloads remote inputs outside of the transcoding workers. Only fully
+/// downloaded inputs are passed to the thread pool.
+pub struct Fetcher {
+    semaphore: Arc<Semaphore>,
+    thread_pool: Arc<ThreadPool>,
+    downloader: Arc<Downloader>,
+    /// Tasks which inputs are being downloaded.
+    active: Arc<Mutex<HashMap<Uuid, Task>>>,
+}
+
+impl Fetcher {
+    pub(crate) fn new(
+        thread_pool: Arc<ThreadPool>,
+        max_concurrent_downloads: usize,
+        downloader: Arc<Downloader>,
+    ) -> Self {
+        Fetcher {
+            semaphore: Arc::new(Semaphore::new(max_concurrent_downloads)),
+            thread_pool,
+            downloader,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn check_url(&self, url: &str) -> Result<(), String> {
+        self.downloader.check_url(url).map(|_| ())
+    }
+
+    pub fn enqueue(&self, task: Task) {
+        let semaphore = Arc::clone(&self.semaphore);
+        let thread_pool = Arc::clone(&self.thread_pool);
+        let downloader = Arc::clone(&self.downloader);
+        let active = Arc::clone(&self.active);
+
+        tokio::spawn(async move {
+            let permit = semaphore.acquire_owned().await;
+            tokio::task::spawn_blocking(move || {
+                if permit.is_err() {
+                    task.fail(ErrorCode::ShuttingDown, SHUTDOWN_MESSAGE);
+                    return;
+                }
+
+                let params = task.params();
+                active.lock().unwrap().insert(task.id(), task.clone());
+                let downloaded = downloader.download(
+                    params.url.as_deref().unwrap_or_default(),
+                    params.headers.as_ref(),
+                    &params.input_path,
+                    params.max_body_size,
+                    params.checksum.as_deref(),
+                );
+                if active.lock().unwrap().remove(&task.id()).is_none() {
+                    // The task has been failed by the shutdown in the meantime
+                    task.remove_input();
+                    return;
+                }
+                if let Err(err) = downloaded {
+                    error!("couldn't download the file: {}", err);
+                    task.fail(err.code, &format!("Couldn't download the file: {}", err));
+                    return;
+                }
+
+                debug!("input has been downloaded to {}", params.input_path);
+                if let Err(err) = task.store_input() {
+                    error!("couldn't store the input: {}", err);
+                    task.fail(
+                        ErrorCode::StorageFailed,
+                        &format!("Couldn't store the input: {}", err),
+                    );
+                    return;
+                }
+
+                if let Err(err) = thread_pool.enqueue(task) {
+                    err.0.fail(ErrorCode::ShuttingDown, SHUTDOWN_MESSAGE);
+                }
+            });
+        });
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.semaphore.is_closed()
+    }
+
+    /// Stops accepting new downloads. Downloads that are waiting for a free slot are failed.
+    pub fn shutdown(&self) {
+        self.semaphore.close();
+    }
+
+    /// Waits for the downloads in progress, the ones which are still running at the deadline
+    /// are reported as failed to their callbacks.
+    pub fn wait(&self, deadline: Instant) {
+        while !self.active.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let abandoned: Vec<Task> = self
+            .active
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, task)| task)
+            .collect();
+        for task in abandoned {
+            warn!(
+                "download for job id={} didn't finish within the grace period",
+                task.id()
+            );
+            task.fail(ErrorCode::ShuttingDown, SHUTDOWN_MESSAGE);
+        }
+    }
+}
+
+/// Download failure with the error code reported to the callback.
+#[derive(Debug)]
+pub struct DownloadError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl DownloadError {
+    fn new(code: ErrorCode, message: String) -> Self {
+        DownloadError { code, message }
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for DownloadError {}
+
+impl From<String> for DownloadError {
+    fn from(message: String) -> Self {
+        DownloadError::new(ErrorCode::DownloadFailed, message)
+    }
+}
+
+impl From<&str> for DownloadError {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(err: io::Error) -> Self {
+        err.to_string().into()
+    }
+}
+
+/// HTTP client for the remote inputs, checks every URL against the policy.
+pub struct Downloader {
+    agent: Agent,
+    policy: Arc<UrlPolicy>,
+    max_redirects: u32,
+    max_resumes: u32,
+    s3: Option<S3Source>,
+}
+
+/// `s3://bucket/key` inputs are downloaded from the configured endpoint with presigned URLs.
+/// The endpoint is trusted, so it's connected to without the policy checks. Only the allowed
+/// buckets may be read, the storage bucket and prefix never, the inputs and results of other
+/// callers are kept there.
+struct S3Source {
+    config: S3Config,
+    buckets: Vec<String>,
+    agent: Agent,
+}
+
+/// Input URL which has passed the checks.
+pub enum InputUrl {
+    /// URL provided by the client, every connection is checked against the policy.
+    Remote(Url),
+    /// Presigned URL built for an `s3://` input, only this URL is fetched without the policy.
+    S3(Url),
+}
+
+impl InputUrl {
+    fn url(&self) -> &Url {
+        match self {
+            InputUrl::Remote(url) | InputUrl::S3(url) => url,
+        }
+    }
+}
+
+impl Downloader {
+    pub(crate) fn new(policy: Arc<UrlPolicy>, options: DownloadOptions) -> Self {
+        let resolver_policy = Arc::clone(&policy);
+        let agent = agent_builder(&options)
+            .resolver(move |netloc: &str| resolver_policy.resolve(netloc))
+            .build();
+
+        Downloader {
+            agent,
+            policy,
+            max_redirects: options.max_redirects,
+            max_resumes: options.max_resumes,
+            s3: None,
+        }
+    }
+
+    /// Enables `s3://bucket/key` inputs from the `buckets` using the endpoint and the
+    /// credentials from the config.
+    pub(crate) fn with_s3(
+        mut self,
+        config: S3Config,
+        buckets: Vec<String>,
+        options: &DownloadOptions,
+    ) -> Self {
+        match Url::parse(config.endpoint()) {
+            Ok(_) => {
+                self.s3 = Some(S3Source {
+                    config,
+                    buckets,
+                    agent: agent_builder(options).build(),
+                })
+            }
+            Err(err) => error!("invalid S3 endpoint, s3:// inputs are disabled: {}", err),
+        }
+        self
+    }
+
+    /// Checks the input URL against the policy. `s3://bucket/key` URLs are turned into the
+    /// presigned URLs of the S3 endpoint.
+    pub fn check_url(&self, url: &str) -> Result<InputUrl, String> {
+        let Some(location) = url.strip_prefix("s3://") else {
+            return self.policy.check_url(url).map(InputUrl::Remote);
+        };
+        let s3 = self.s3.as_ref().ok_or("s3:// URLs are not supported")?;
+        let (bucket, key) = location
+            .split_once('/')
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .ok_or("s3:// URL must contain bucket and key")?;
+        let is_storage = bucket == s3.config.bucket && key.starts_with(&s3.config.prefix);
+        if is_storage || !s3.buckets.iter().any(|allowed| allowed == bucket) {
+            return Err(format!("bucket {} is not allowed", bucket));
+        }
+        s3.config
+            .presign("GET", bucket, key, S3_URL_TTL)
+            .map(InputUrl::S3)
+    }
+
+    /// Downloads the file sending the custom headers. If the transfer is interrupted, it's
+    /// resumed with a `Range` request, or started over if the server doesn't support ranges.
+    /// The size is verified against `Content-Length` and the contents against `checksum`
+    /// (SHA-256 hex) if it's provided.
+    pub fn download(
+        &self,
+        url: &str,
+        headers: Option<&Headers>,
+        output_path: &str,
+        max_size: usize,
+        checksum: Option<&str>,
+    ) -> Result<(), DownloadError> {
+        let origin = self.check_url(url)?;
+        let (mut url, mut response) = self.get(&origin, origin.url().clone(), headers, None)?;
+        if response.status() != 200 {
+            return Err(format!("Failed to download file: HTTP {}", response.status()).into());
+        }
+
+        let mut total = content_length(&response);
+        check_content_length(total, max_size)?;
+        check_content_type(&response)?;
+        let mut validator = range_validator(&response);
+
+        let mut file = File::create(output_path)?;
+        let mut written = 0;
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        let mut resumes = 0;
+        while let Some(err) = copy_body(response, &mut file, &mut written, &mut head, max_size)? {
+            if resumes >= self.max_resumes {
+                return Err(format!("Failed to download file: {}", err).into());
+            }
+            resumes += 1;
+            warn!(
+                "download of {} was interrupted after {} bytes: {}, resuming",
+                redacted_url(&url),
+                written,
+                err
+            );
+
+            let range = (written, validator.as_deref());
+            (url, response) = self.get(&origin, url, headers, Some(range))?;
+            match response.status() {
+                206 if content_range_start(&response) == Some(written) => {}
+                200 => {
+                    debug!("server doesn't support ranges, starting the download over");
+                    file.set_len(0)?;
+                    file.seek(SeekFrom::Start(0))?;
+                    written = 0;
+                    head.clear();
+                    total = content_length(&response);
+                    check_content_length(total, max_size)?;
+                    validator = range_validator(&response);
+                }
+                status => return Err(format!("Failed to resume download: HTTP {}", status).into()),
+            }
+        }
+        file.flush()?;
+
+        if let Some(total) = total {
+            if written != total {
+                return Err(
+                    format!("Incomplete download: got {} of {} bytes", written, total).into(),
+                );
+            }
+        }
+
+        if let Some(expected) = checksum {
+            let actual = file_digest(Path::new(output_path))?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(DownloadError::new(
+                    ErrorCode::ChecksumMismatch,
+                    format!("Checksum mismatch: expected {}, got {}", expected, actual),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Opens the response body to be read while it's being downloaded. Interrupted transfers
+    /// can't be resumed in this case.
+    pub fn open(
+        &self,
+        url: &str,
+        headers: Option<&Headers>,
+        max_size: usize,
+    ) -> Result<BodyReader, DownloadError> {
+        let origin = self.check_url(url)?;
+        let (_, response) = self.get(&origin, origin.url().clone(), headers, None)?;
+        if response.status() != 200 {
+            return Err(format!("Failed to download file: HTTP {}", response.status()).into());
+        }
+        check_content_length(content_length(&response), max_size)?;
+        check_content_type(&response)?;
+
+        Ok(BodyReader {
+            reader: response.into_reader(),
+            read: 0,
+            head: Vec::with_capacity(SNIFF_LEN),
+            max_size,
+            error: None,
+        })
+    }
+
+    /// Sends the GET request following the redirects, returns the final URL and the response.
+    /// Sensitive headers are sent only to the origin of the initial URL and are dropped after
+    /// a redirect to another origin. Redirects from the presigned S3 URLs are checked against
+    /// the policy like any other URL.
+    fn get(
+        &self,
+        origin: &InputUrl,
+        mut url: Url,
+        headers: Option<&Headers>,
+        range: Option<(u64, Option<&str>)>,
+    ) -> Result<(Url, Response), DownloadError> {
+        let mut redirects = 0;
+        loop {
+            let same_origin = url.origin() == origin.url().origin();
+            // Presigned URLs are signed for the host only, custom headers aren't sent to S3.
+            let (agent, headers) = match (origin, &self.s3) {
+                (InputUrl::S3(presigned), Some(s3)) if url == *presigned => (&s3.agent, None),
+                (InputUrl::S3(_), _) => (&self.agent, None),
+                (InputUrl::Remote(_), _) => (&self.agent, headers),
+            };
+            debug!(
+                "downloading {} with headers {:?}",
+                redacted_url(&url),
+                headers.unwrap_or(&Headers::default())
+            );
+
+            let mut request = agent.get(url.as_str());
+            for (name, value) in headers.iter().flat_map(|headers| headers.iter()) {
+                if same_origin || !Headers::is_sensitive(name) {
+                    request = request.set(name, value);
+                }
+            }
+            if let Some((start, validator)) = range {
+                request = request.set("Range", &format!("bytes={}-", start));
+                if let Some(validator) = validator {
+                    request = request.set("If-Range", validator);
+                }
+            }
+
+            let response = match request.call() {
+                Ok(response) => response,
+                Err(UreqError::Status(code, _response)) => {
+                    return Err(format!("Failed to download file: HTTP {}", code).into());
+                }
+                // The error contains the whole URL, presigned URLs must not end up in the logs
+                Err(UreqError::Transport(err)) => {
+                    return Err(format!(
+                        "Failed to make request to {}: {}",
+                        redacted_url(&url),
+                        err.kind()
+                    )
+                    .into());
+                }
+            };
+            if !(300..400).contains(&response.status()) {
+                return Ok((url, response));
+            }
+
+            let Some(location) = response.header("Location") else {
+                return Err("Failed to download file: redirect without Location".into());
+            };
+            redirects += 1;
+            if redirects > self.max_redirects {
+                return Err("Failed to download file: too many redirects".into());
+            }
+            let next = url.join(location).map_err(|err| err.to_string())?;
+            url = self.policy.check_url(next.as_str())?;
+        }
+    }
+}
+
+/// Streamed response body with the same checks as the downloaded files. The first error is
+/// kept to be reported after the consumer stops reading.
+pub struct BodyReader {
+    reader: Box<dyn Read + Send + Sync>,
+    read: u64,
+    head: Vec<u8>,
+    max_size: usize,
+    error: Option<DownloadError>,
+}
+
+impl BodyReader {
+    pub fn take_error(&mut self) -> Option<DownloadError> {
+        self.error.take()
+    }
+
+    fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, DownloadError> {
+        let bytes_read = self
+            .reader
+            .read(buf)
+            .map_err(|err| format!("Failed to download file: {}", err))?;
+        if bytes_read == 0 {
+            if self.head.len() < SNIFF_LEN {
+                check_media(&self.head)?;
+            }
+            return Ok(0);
+        }
+
+        self.read += bytes_read as u64;
+        if self.read > self.max_size as u64 {
+            return Err(DownloadError::new(
+                ErrorCode::InputTooLarge,
+                "Response body exceeds the limit".to_string(),
+            ));
+        }
+
+        if self.head.len() < SNIFF_LEN {
+            let len = (SNIFF_LEN - self.head.len()).min(bytes_read);
+            self.head.extend_from_slice(&buf[..len]);
+            if self.head.len() == SNIFF_LEN {
+                check_media(&self.head)?;
+            }
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(err) = &self.error {
+            return Err(io::Error::other(err.message.clone()));
+        }
+
+        self.try_read(buf).map_err(|err| {
+            let io_err = io::Error::other(err.message.clone());
+            self.error = Some(err);
+            io_err
+        })
+    }
+}
+
+/// Appends the response body to the file. The first `SNIFF_LEN` bytes are collected to `head`
+/// to check the contents as soon as possible. Returns the read error if the transfer was
+/// interrupted, so the download can be resumed.
+fn copy_body(
+    response: Response,
+    file: &mut File,
+    written: &mut u64,
+    head: &mut Vec<u8>,
+    max_size: usize,
+) -> Result<Option<io::Error>, DownloadError> {
+    let mut reader = response.into_reader();
+    let mut buffer = vec![0; 8 * 1024]; // Read in 8KB chunks
+
+    loop {
+        let bytes_read = match reader.read(&mut buffer) {
+            Ok(0) => {
+                if head.len() < SNIFF_LEN {
+                    check_media(head)?;
+                }
+                return Ok(None);
+            }
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Ok(Some(err)),
+        };
+
+        *written += bytes_read as u64;
+        if *written > max_size as u64 {
+            return Err(DownloadError::new(
+                ErrorCode::InputTooLarge,
+                "Response body exceeds the limit".to_string(),
+            ));
+        }
+
+        if head.len() < SNIFF_LEN {
+            let len = (SNIFF_LEN - head.len()).min(bytes_read);
+            head.extend_from_slice(&buffer[..len]);
+            if head.len() == SNIFF_LEN {
+                check_media(head)?;
+            }
+        }
+
+        file.write_all(&buffer[..bytes_read])?;
+    }
+}
+
+fn agent_builder(options: &DownloadOptions) -> AgentBuilder {
+    // Redirects are followed manually to check every URL against the policy.
+    AgentBuilder::new()
+        .timeout_connect(options.connect_timeout)
+        .timeout_read(options.read_timeout)
+        .redirects(0)
+}
+
+/// URL without the query, which contains the signature of the presigned URLs.
+pub fn redacted_url(url: &Url) -> &str {
+    &url[..url::Position::AfterPath]
+}
+
+fn check_content_length(length: Option<u64>, max_size: usize) -> Result<(), DownloadError> {
+    match length {
+        Some(length) if length > max_size as u64 => Err(DownloadError::new(
+            ErrorCode::InputTooLarge,
+            format!(
+                "Input size {} bytes exceeds the limit of {} bytes",
+                length, max_size
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Rejects the responses which are obviously documents, e.g. HTML error pages. `Content-Type`
+/// of media files is often wrong, so only the text types are rejected here.
+fn check_content_type(response: &Response) -> Result<(), DownloadError> {
+    // Response::content_type() defaults to text/plain, so the header is read directly.
+    let content_type = response
+        .header("Content-Type")
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    if content_type.starts_with("text/") || TEXT_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(DownloadError::new(
+            ErrorCode::UnsupportedMediaType,
+            format!("Unsupported input content type: {}", content_type),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the first bytes of the input. Unknown binary formats are let through,
+/// FFmpeg supports much more formats than can be sniffed.
+fn check_media(head: &[u8]) -> Result<(), DownloadError> {
+    if head.is_empty() {
+        return Err(DownloadError::new(
+            ErrorCode::UnsupportedMediaType,
+            "Input is empty".to_string(),
+        ));
+    }
+
+    let message = match infer::get(head) {
+        Some(kind) if matches!(kind.matcher_type(), MatcherType::Audio | MatcherType::Video) => {
+            return Ok(())
+        }
+        Some(kind) => format!("Input is not an audio or video file: {}", kind.mime_type()),
+        None if looks_like_text(head) => {
+            "Input is a text document, not an audio or video file".to_string()
+        }
+        None => return Ok(()),
+    };
+    Err(DownloadError::new(ErrorCode::UnsupportedMediaType, message))
+}
+
+/// Markup and JSON documents, which is what error pages usually are.
+fn looks_like_text(head: &[u8]) -> bool {
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    matches!(
+        head.iter().find(|byte| !byte.is_ascii_whitespace()),
+        Some(b'<' | b'{' | b'[')
+    )
+}
+
+fn content_length(response: &Response) -> Option<u64> {
+    response
+        .header("Content-Length")
+        .and_then(|len| len.trim().parse().ok())
+}
+
+/// Returns the value for `If-Range`, so the resumed download fails over to the full response
+/// if the file has changed. Weak ETags can't be used with `If-Range`.
+fn range_validator(response: &Response) -> Option<String> {
+    response
+        .header("ETag")
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| response.header("Last-Modified"))
+        .map(str::to_string)
+}
+
+/// Returns the first byte position from `Content-Range: bytes <start>-<end>/<total>`.
+fn content_range_start(response: &Response) -> Option<u64> {
+    response
+        .header("Content-Range")?
+        .trim()
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::url_policy::{DEFAULT_DENIED_CIDRS, DEFAULT_SCHEMES};
+
+    fn downloader() -> Downloader {
+        let options = DownloadOptions {
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            max_redirects: 0,
+            max_resumes: 0,
+        };
+        let policy = UrlPolicy::new(DEFAULT_SCHEMES, "", "", "", DEFAULT_DENIED_CIDRS).unwrap();
+        let config = S3Config {
+            endpoint: "http://minio:9000".to_string(),
+            region: "us-east-1".to_string(),
+            bucket: "transcoder".to_string(),
+            access_key_id: "key".to_string(),
+            secret_access_key: "secret".to_string(),
+            prefix: "results/".to_string(),
+        };
+        Downloader::new(Arc::new(policy), options).with_s3(
+            config,
+            vec!["assets".to_string(), "transcoder".to_string()],
+            &options,
+        )
+    }
+
+    #[test]
+    fn s3_inputs_from_allowed_buckets() {
+        let downloader = downloader();
+        match downloader.check_url("s3://assets/music/a.mp3") {
+            Ok(InputUrl::S3(url)) => {
+                assert_eq!(url.host_str(), Some("minio"));
+                assert_eq!(url.path(), "/assets/music/a.mp3");
+            }
+            _ => panic!("s3:// input must be presigned"),
+        }
+        // The storage bucket outside of the storage prefix may be allowed
+        assert!(downloader.check_url("s3://transcoder/inputs/a.mp3").is_ok());
+    }
+
+    #[test]
+    fn s3_inputs_from_other_buckets_are_denied() {
+        let downloader = downloader();
+        assert!(downloader.check_url("s3://private/a.mp3").is_err());
+        assert!(downloader.check_url("s3://assets").is_err());
+        assert!(downloader.check_url("s3:///a.mp3").is_err());
+    }
+
+    #[test]
+    fn s3_storage_objects_are_denied() {
+        let downloader = downloader();
+        let id = uuid::Uuid::new_v4().to_string();
+        for name in [
+            crate::filepath::out_file_name(&id),
+            crate::filepath::in_file_name(&id),
+        ] {
+            let url = format!("s3://transcoder/results/{}", name);
+            assert!(downloader.check_url(&url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn plain_urls_to_the_s3_endpoint_are_checked() {
+        let downloader = downloader();
+        // `minio` resolves to a private address at connection time, a literal address is
+        // rejected right away
+        assert!(matches!(
+            downloader.check_url("http://minio:9000/transcoder/results/a"),
+            Ok(InputUrl::Remote(_))
+        ));
+        assert!(downloader
+            .check_url("http://10.0.0.1:9000/assets/a.mp3")
+            .is_err());
+    }
+}
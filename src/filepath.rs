@@ -1,11 +1,95 @@
 use std::path::{Path, PathBuf};
 
 pub const EXT: &str = "atranscoder";
+const MAX_FILE_NAME_LEN: usize = 200;
+
+/// MIME types and file extensions of the audio muxers. FFmpeg doesn't know the MIME types of
+/// some of them, and reports the video ones for the containers like MP4.
+const MUXER_TYPES: [(&str, &str, &str); 16] = [
+    ("ac3", "audio/ac3", "ac3"),
+    ("adts", "audio/aac", "aac"),
+    ("aiff", "audio/aiff", "aiff"),
+    ("amr", "audio/amr", "amr"),
+    ("caf", "audio/x-caf", "caf"),
+    ("flac", "audio/flac", "flac"),
+    ("ipod", "audio/mp4", "m4a"),
+    ("matroska", "audio/x-matroska", "mka"),
+    ("mp3", "audio/mpeg", "mp3"),
+    ("mp4", "audio/mp4", "m4a"),
+    ("ogg", "audio/ogg", "ogg"),
+    ("opus", "audio/ogg", "opus"),
+    ("spx", "audio/ogg", "spx"),
+    ("wav", "audio/wav", "wav"),
+    ("webm", "audio/webm", "webm"),
+    ("wv", "audio/x-wavpack", "wv"),
+];
+
+pub fn in_file_name(task_id: &str) -> String {
+    format!("{}.in.atranscoder", task_id)
+}
+
+pub fn out_file_name(task_id: &str) -> String {
+    format!("{}.out.atranscoder", task_id)
+}
 
 pub fn in_file_path(work_dir: &str, task_id: String) -> PathBuf {
-    Path::new(work_dir).join(format!("{}.in.atranscoder", task_id))
+    Path::new(work_dir).join(in_file_name(&task_id))
 }
 
 pub fn out_file_path(work_dir: &str, task_id: String) -> PathBuf {
-    Path::new(work_dir).join(format!("{}.out.atranscoder", task_id))
+    Path::new(work_dir).join(out_file_name(&task_id))
+}
+
+/// Returns the MIME type and the file extension for the output of the muxer.
+pub fn muxer_type(name: &str, mime_types: &[&str], extensions: &[&str]) -> (String, String) {
+    if let Some((_, mime_type, extension)) = MUXER_TYPES.iter().find(|(muxer, _, _)| *muxer == name)
+    {
+        return (mime_type.to_string(), extension.to_string());
+    }
+
+    (
+        mime_types
+            .first()
+            .map_or("application/octet-stream", |mime_type| mime_type)
+            .to_string(),
+        extensions
+            .first()
+            .map_or(name, |extension| extension)
+            .to_string(),
+    )
+}
+
+/// Strips the directories, quotes and control characters from the file name.
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(MAX_FILE_NAME_LEN)
+        .collect();
+    let name = name.trim().trim_matches('.').trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// File name without the extension, used to name the result after the input.
+pub fn file_stem(name: &str) -> Option<String> {
+    let name = sanitize_file_name(name)?;
+    let stem = Path::new(&name).file_stem()?.to_str()?;
+    sanitize_file_name(stem)
+}
+
+/// Adds the extension to the file name if it has none.
+pub fn result_file_name(name: &str, extension: &str) -> String {
+    if Path::new(name).extension().is_some() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, extension)
+    }
+}
+
+/// Guesses the MIME type from the first bytes of the file contents.
+pub fn guess_mime_type(buffer: &[u8]) -> String {
+    infer::get(buffer).map_or("application/octet-stream".to_string(), |t| {
+        t.mime_type().to_string()
+    })
 }
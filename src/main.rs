@@ -1,32 +1,146 @@
 use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use tracing::error;
 use tracing_subscriber::EnvFilter;
 
+use crate::auth::ApiKeys;
+use crate::callback::Notifier;
+use crate::dead_letter::DeadLetterStore;
+use crate::fetcher::{DownloadOptions, Downloader, Fetcher};
+use crate::registry::JobRegistry;
 use crate::server::Server;
+use crate::signing::LinkSigner;
+use crate::storage::{LocalStorage, S3Config, S3Storage, Storage};
+use crate::task::TaskContext;
 use crate::thread_pool::ThreadPool;
+use crate::uploader::Uploader;
+use crate::url_policy::{UrlPolicy, DEFAULT_DENIED_CIDRS, DEFAULT_SCHEMES};
 
+mod auth;
+mod avio;
+mod callback;
+mod cleanup;
+mod dead_letter;
+mod disk;
 mod dto;
+mod fetcher;
 mod filepath;
+mod metrics;
+mod registry;
 mod server;
+mod signing;
+mod storage;
 mod task;
 mod thread_pool;
 mod transcoder;
+mod uploader;
+mod url_policy;
 
 const WORK_DIR_IN_OUT_LIFETIME: u64 = 60 * 60;
+const SHUTDOWN_GRACE_PERIOD: u64 = 30;
+const MAX_CONCURRENT_DOWNLOADS: usize = 16;
+const DOWNLOAD_CONNECT_TIMEOUT: u64 = 10;
+const DOWNLOAD_READ_TIMEOUT: u64 = 60;
+const DOWNLOAD_MAX_REDIRECTS: u32 = 5;
+const DOWNLOAD_RESUME_ATTEMPTS: u32 = 3;
+const CALLBACK_ATTEMPTS: u32 = 3;
+const CALLBACK_TIMEOUT: u64 = 60;
+const UPLOAD_ATTEMPTS: u32 = 3;
+const S3_REGION: &str = "us-east-1";
 
-#[tokio::main]
-async fn main() {
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Cannot start the runtime");
+    let deadline = runtime.block_on(run());
+    // Whatever is still running (e.g. abandoned downloads) must not delay the exit
+    runtime.shutdown_timeout(deadline.saturating_duration_since(Instant::now()));
+}
+
+/// Runs the service until it's stopped, returns the shutdown deadline.
+async fn run() -> Instant {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_env("LOG_LEVEL"))
         .init();
 
     let addr = env::var("LISTEN").unwrap_or_else(|_| "0.0.0.0:8090".to_string());
-    let pool = ThreadPool::new(
+    let pool = Arc::new(ThreadPool::new(
         env::var("NUM_WORKERS")
             .ok()
             .and_then(|val| val.parse::<usize>().ok())
             .filter(|&val| val > 0),
+    ));
+    let s3_region = env::var("S3_REGION").unwrap_or_else(|_| S3_REGION.to_string());
+    let s3_config = S3Config {
+        endpoint: env::var("S3_ENDPOINT")
+            .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", s3_region)),
+        region: s3_region,
+        bucket: env::var("S3_BUCKET").unwrap_or_default(),
+        access_key_id: env::var("S3_ACCESS_KEY_ID")
+            .or_else(|_| env::var("AWS_ACCESS_KEY_ID"))
+            .unwrap_or_default(),
+        secret_access_key: env::var("S3_SECRET_ACCESS_KEY")
+            .or_else(|_| env::var("AWS_SECRET_ACCESS_KEY"))
+            .unwrap_or_default(),
+        prefix: env::var("S3_PREFIX").unwrap_or_default(),
+    };
+    let download_options = DownloadOptions {
+        connect_timeout: Duration::from_secs(
+            env::var("DOWNLOAD_CONNECT_TIMEOUT_SEC")
+                .ok()
+                .and_then(|val| val.parse::<u64>().ok())
+                .map_or(DOWNLOAD_CONNECT_TIMEOUT, |val| val),
+        ),
+        read_timeout: Duration::from_secs(
+            env::var("DOWNLOAD_READ_TIMEOUT_SEC")
+                .ok()
+                .and_then(|val| val.parse::<u64>().ok())
+                .map_or(DOWNLOAD_READ_TIMEOUT, |val| val),
+        ),
+        max_redirects: env::var("DOWNLOAD_MAX_REDIRECTS")
+            .ok()
+            .and_then(|val| val.parse::<u32>().ok())
+            .map_or(DOWNLOAD_MAX_REDIRECTS, |val| val),
+        max_resumes: env::var("DOWNLOAD_RESUME_ATTEMPTS")
+            .ok()
+            .and_then(|val| val.parse::<u32>().ok())
+            .map_or(DOWNLOAD_RESUME_ATTEMPTS, |val| val),
+    };
+    let url_policy = Arc::new(
+        UrlPolicy::new(
+            &env::var("URL_ALLOWED_SCHEMES").unwrap_or_else(|_| DEFAULT_SCHEMES.to_string()),
+            &env::var("URL_ALLOWED_HOSTS").unwrap_or_default(),
+            &env::var("URL_DENIED_HOSTS").unwrap_or_default(),
+            &env::var("URL_ALLOWED_CIDRS").unwrap_or_default(),
+            &env::var("URL_DENIED_CIDRS").unwrap_or_else(|_| DEFAULT_DENIED_CIDRS.to_string()),
+        )
+        .expect("Invalid URL policy"),
     );
+    let mut downloader = Downloader::new(Arc::clone(&url_policy), download_options);
+    if !s3_config.access_key_id.is_empty() && !s3_config.secret_access_key.is_empty() {
+        let buckets = env::var("S3_INPUT_BUCKETS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|bucket| !bucket.is_empty())
+            .map(str::to_string)
+            .collect();
+        downloader = downloader.with_s3(s3_config.clone(), buckets, &download_options);
+    }
+    let downloader = Arc::new(downloader);
+    let fetcher = Arc::new(Fetcher::new(
+        Arc::clone(&pool),
+        env::var("DOWNLOAD_CONCURRENCY")
+            .ok()
+            .and_then(|val| val.parse::<usize>().ok())
+            .filter(|&val| val > 0)
+            .map_or(MAX_CONCURRENT_DOWNLOADS, |val| val),
+        Arc::clone(&downloader),
+    ));
     let temp_dir = env::var("TEMP_DIR").unwrap_or_else(|_| {
         env::temp_dir()
             .to_str()
@@ -34,14 +148,92 @@ async fn main() {
             .parse()
             .unwrap()
     });
-    Server::new(pool, temp_dir)
-        .start_cleanup_task(
-            env::var("RESULT_TTL_SEC")
+    let result_ttl = env::var("RESULT_TTL_SEC")
+        .ok()
+        .and_then(|val| val.parse::<u64>().ok())
+        .map_or(WORK_DIR_IN_OUT_LIFETIME, |val| val);
+    let mut notifier = Notifier::new(
+        env::var("CALLBACK_SECRET").ok(),
+        env::var("PUBLIC_URL").ok(),
+        env::var("CALLBACK_ATTEMPTS")
+            .ok()
+            .and_then(|val| val.parse::<u32>().ok())
+            .map_or(CALLBACK_ATTEMPTS, |val| val),
+        Duration::from_secs(
+            env::var("CALLBACK_TIMEOUT_SEC")
                 .ok()
                 .and_then(|val| val.parse::<u64>().ok())
-                .map_or(WORK_DIR_IN_OUT_LIFETIME, |val| val),
-        )
-        .serve(&addr)
-        .await
-        .expect("Cannot bind the addr")
+                .map_or(CALLBACK_TIMEOUT, |val| val),
+        ),
+        &download_options,
+    );
+    let dead_letter_dir = env::var("DEAD_LETTER_DIR")
+        .map_or_else(|_| Path::new(&temp_dir).join("dead_letters"), PathBuf::from);
+    if let Some(secret) = env::var("DOWNLOAD_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+    {
+        let max_ttl = env::var("DOWNLOAD_LINK_TTL_SEC")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        notifier = notifier.with_link_signer(LinkSigner::new(&secret, max_ttl));
+    }
+    match DeadLetterStore::new(dead_letter_dir) {
+        Ok(store) => notifier = notifier.with_dead_letters(store),
+        Err(err) => error!("couldn't open dead-letter store: {}", err),
+    }
+    let storage: Arc<dyn Storage> = match env::var("STORAGE_BACKEND").as_deref() {
+        Ok("s3") => Arc::new(
+            S3Storage::new(s3_config.clone(), &download_options).expect("Invalid S3 configuration"),
+        ),
+        _ => Arc::new(LocalStorage::new(&temp_dir)),
+    };
+    let context = Arc::new(TaskContext {
+        registry: JobRegistry::new(result_ttl),
+        notifier,
+        uploader: Uploader::new(
+            env::var("UPLOAD_ATTEMPTS")
+                .ok()
+                .and_then(|val| val.parse::<u32>().ok())
+                .map_or(UPLOAD_ATTEMPTS, |val| val),
+            url_policy,
+            &download_options,
+        ),
+        downloader,
+        storage,
+    });
+    let api_keys = ApiKeys::load(
+        env::var("API_KEYS_FILE").ok().as_deref(),
+        env::var("API_KEYS").ok().as_deref(),
+    )
+    .expect("Invalid API keys");
+    let grace_period = Duration::from_secs(
+        env::var("SHUTDOWN_GRACE_SEC")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .map_or(SHUTDOWN_GRACE_PERIOD, |val| val),
+    );
+    let deadline = Server::new(
+        Arc::clone(&pool),
+        Arc::clone(&fetcher),
+        context,
+        temp_dir,
+        api_keys,
+    )
+    .start_cleanup_task(result_ttl)
+    .serve(&addr, grace_period)
+    .await
+    .expect("Cannot bind the addr");
+
+    // Downloads and transcoding share the rest of the grace period
+    fetcher.shutdown();
+    let downloads = tokio::task::spawn_blocking(move || fetcher.wait(deadline));
+    let transcoding = tokio::task::spawn_blocking(move || {
+        pool.shutdown(deadline.saturating_duration_since(Instant::now()))
+    });
+    let (downloads, transcoding) = tokio::join!(downloads, transcoding);
+    downloads.expect("Cannot stop the downloads");
+    transcoding.expect("Cannot stop the thread pool");
+    deadline
 }
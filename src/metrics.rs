@@ -0,0 +1,44 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters exposed at `/metrics` in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    cleanup_runs: AtomicU64,
+    cleanup_files: AtomicU64,
+    cleanup_bytes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_cleanup(&self, files: u64, bytes: u64) {
+        self.cleanup_runs.fetch_add(1, Ordering::Relaxed);
+        self.cleanup_files.fetch_add(files, Ordering::Relaxed);
+        self.cleanup_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in [
+            (
+                "atranscoder_cleanup_runs_total",
+                "Number of the cleanup runs.",
+                &self.cleanup_runs,
+            ),
+            (
+                "atranscoder_cleanup_files_total",
+                "Number of the input and result files removed by the cleanup.",
+                &self.cleanup_files,
+            ),
+            (
+                "atranscoder_cleanup_bytes_total",
+                "Bytes freed in the working directory by the cleanup.",
+                &self.cleanup_bytes,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
@@ -0,0 +1,717 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::callback::CallbackTarget;
+use crate::dto::{CallbackDelivery, ErrorCode};
+use crate::task::{JobResult, TaskParams};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub struct JobError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// How long the job result is kept.
+#[derive(Clone, Copy, Default)]
+pub struct Retention {
+    /// Overrides the default result TTL.
+    pub ttl: Option<Duration>,
+    /// The result is removed after it has been downloaded once.
+    pub delete_on_download: bool,
+}
+
+struct Job {
+    status: JobStatus,
+    error: Option<JobError>,
+    callback: Option<CallbackDelivery>,
+    dedup_key: Option<String>,
+    result_ttl: Duration,
+    delete_on_download: bool,
+    result: Option<Arc<JobResult>>,
+    /// Callbacks of the requests deduplicated to this job, sent when it finishes.
+    callbacks: Vec<CallbackTarget>,
+    /// Name of the API key which created the job.
+    owner: Option<String>,
+    updated_at: SystemTime,
+}
+
+/// Snapshot of the job state.
+pub struct JobInfo {
+    pub status: JobStatus,
+    pub error: Option<JobError>,
+    pub callback: Option<CallbackDelivery>,
+    pub delete_on_download: bool,
+    pub mime_type: Option<String>,
+    pub file_name: Option<String>,
+    pub owner: Option<String>,
+}
+
+/// Existing job which a new request has been deduplicated to.
+pub struct Duplicate {
+    pub id: Uuid,
+    /// Callback of the new request with the result to be sent right away if the job is
+    /// already done. Otherwise the callback is sent when the job finishes.
+    pub done: Option<(CallbackTarget, Arc<JobResult>)>,
+}
+
+struct Batch {
+    callback: CallbackTarget,
+    items: Vec<Uuid>,
+    pending: HashSet<Uuid>,
+    errors: HashMap<Uuid, JobError>,
+}
+
+/// Batch which jobs are all finished.
+pub struct CompletedBatch {
+    pub id: Uuid,
+    pub callback: CallbackTarget,
+    pub items: Vec<BatchItem>,
+}
+
+pub struct BatchItem {
+    pub id: Uuid,
+    pub error: Option<JobError>,
+    /// How long the result is still kept.
+    pub result_ttl: Duration,
+}
+
+#[derive(Default)]
+struct Jobs {
+    jobs: HashMap<Uuid, Job>,
+    keys: HashMap<String, Uuid>,
+    batches: HashMap<Uuid, Batch>,
+}
+
+impl Job {
+    /// How long the result is still kept, counting from now.
+    fn remaining_ttl(&self) -> Duration {
+        match self.status {
+            JobStatus::Done | JobStatus::Failed => {
+                let age = SystemTime::now()
+                    .duration_since(self.updated_at)
+                    .unwrap_or_default();
+                self.result_ttl.saturating_sub(age)
+            }
+            JobStatus::Queued | JobStatus::Running => self.result_ttl,
+        }
+    }
+}
+
+impl Batch {
+    fn complete(
+        mut self,
+        id: Uuid,
+        jobs: &HashMap<Uuid, Job>,
+        default_ttl: Duration,
+    ) -> CompletedBatch {
+        let items = self
+            .items
+            .into_iter()
+            .map(|item| BatchItem {
+                id: item,
+                error: self.errors.remove(&item),
+                result_ttl: jobs.get(&item).map_or(default_ttl, Job::remaining_ttl),
+            })
+            .collect();
+
+        CompletedBatch {
+            id,
+            callback: self.callback,
+            items,
+        }
+    }
+}
+
+/// Keeps track of the jobs known to this instance and their results.
+pub struct JobRegistry {
+    inner: Mutex<Jobs>,
+    result_ttl: Duration,
+}
+
+impl JobRegistry {
+    pub(crate) fn new(result_ttl: u64) -> Self {
+        JobRegistry {
+            inner: Mutex::new(Jobs::default()),
+            result_ttl: Duration::from_secs(result_ttl),
+        }
+    }
+
+    /// Registers a new job. If `dedup_key` is provided and an identical job of the same owner
+    /// is queued, running or its result is still available, the new job is not registered and
+    /// the existing job is returned instead, the callback is attached to it. The result TTL of
+    /// the existing job is extended to cover the retention of the new request.
+    pub fn claim(
+        &self,
+        id: Uuid,
+        dedup_key: Option<String>,
+        retention: Retention,
+        owner: Option<String>,
+        callback: Option<CallbackTarget>,
+    ) -> Option<Duplicate> {
+        let mut inner = self.inner.lock().unwrap();
+        let dedup_key = dedup_key.map(|key| match &owner {
+            Some(owner) => format!("{}\n{}", owner, key),
+            None => key,
+        });
+
+        if let Some(key) = &dedup_key {
+            if let Some(existing) = inner.keys.get(key).copied() {
+                if let Some(job) = inner
+                    .jobs
+                    .get_mut(&existing)
+                    .filter(|job| self.is_reusable(job))
+                {
+                    // The result must be kept as long as the new request asked for
+                    let ttl = retention.ttl.unwrap_or(self.result_ttl);
+                    let needed = match job.status {
+                        JobStatus::Done => {
+                            SystemTime::now()
+                                .duration_since(job.updated_at)
+                                .unwrap_or_default()
+                                + ttl
+                        }
+                        _ => ttl,
+                    };
+                    job.result_ttl = job.result_ttl.max(needed);
+
+                    let done = match (job.status, callback) {
+                        (JobStatus::Done, Some(callback)) => {
+                            job.result.clone().map(|result| (callback, result))
+                        }
+                        (_, Some(callback)) => {
+                            job.callbacks.push(callback);
+                            None
+                        }
+                        (_, None) => None,
+                    };
+                    return Some(Duplicate { id: existing, done });
+                }
+            }
+            inner.keys.insert(key.clone(), id);
+        }
+
+        inner.jobs.insert(
+            id,
+            Job {
+                status: JobStatus::Queued,
+                error: None,
+                callback: None,
+                dedup_key,
+                result_ttl: retention.ttl.unwrap_or(self.result_ttl),
+                delete_on_download: retention.delete_on_download,
+                result: None,
+                callbacks: Vec::new(),
+                owner,
+                updated_at: SystemTime::now(),
+            },
+        );
+        None
+    }
+
+    pub fn set_status(&self, id: Uuid, status: JobStatus) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(job) = inner.jobs.get_mut(&id) {
+            job.status = status;
+            job.updated_at = SystemTime::now();
+        }
+    }
+
+    pub fn set_callback_delivery(&self, id: Uuid, delivery: CallbackDelivery) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(job) = inner.jobs.get_mut(&id) {
+            job.callback = Some(delivery);
+        }
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<JobInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner.jobs.get(&id).map(|job| JobInfo {
+            status: job.status,
+            error: job.error.clone(),
+            callback: job.callback.clone(),
+            delete_on_download: job.delete_on_download,
+            mime_type: job.result.as_ref().map(|result| result.mime_type.clone()),
+            file_name: job.result.as_ref().map(|result| result.file_name.clone()),
+            owner: job.owner.clone(),
+        })
+    }
+
+    /// Returns how long the result of the job is still kept.
+    pub fn result_ttl(&self, id: Uuid) -> Duration {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .jobs
+            .get(&id)
+            .map_or(self.result_ttl, Job::remaining_ttl)
+    }
+
+    /// Keeps the result to be sent to the callbacks of the deduplicated requests and its type
+    /// to be sent with the downloads.
+    pub fn set_result(&self, id: Uuid, result: Arc<JobResult>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(job) = inner.jobs.get_mut(&id) {
+            job.result = Some(result);
+        }
+    }
+
+    /// Returns the callbacks of the deduplicated requests, should be called after the job has
+    /// finished. Requests deduplicated after that get the result right away.
+    pub fn take_callbacks(&self, id: Uuid) -> Vec<CallbackTarget> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .jobs
+            .get_mut(&id)
+            .map(|job| std::mem::take(&mut job.callbacks))
+            .unwrap_or_default()
+    }
+
+    /// Marks the job as done, or as failed if `error` is provided. Returns the batches which
+    /// are complete after this job.
+    pub fn finish(&self, id: Uuid, error: Option<JobError>) -> Vec<CompletedBatch> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        let Some(job) = inner.jobs.get_mut(&id) else {
+            return Vec::new();
+        };
+
+        job.status = if error.is_some() {
+            JobStatus::Failed
+        } else {
+            JobStatus::Done
+        };
+        job.error.clone_from(&error);
+        job.updated_at = SystemTime::now();
+
+        // Failed jobs should never be reused.
+        if job.status == JobStatus::Failed {
+            if let Some(key) = job.dedup_key.take() {
+                if inner.keys.get(&key) == Some(&id) {
+                    inner.keys.remove(&key);
+                }
+            }
+        }
+
+        let mut completed = Vec::new();
+        for (batch_id, batch) in inner.batches.iter_mut() {
+            if !batch.pending.remove(&id) {
+                continue;
+            }
+            if let Some(error) = &error {
+                batch.errors.insert(id, error.clone());
+            }
+            if batch.pending.is_empty() {
+                completed.push(*batch_id);
+            }
+        }
+
+        completed
+            .into_iter()
+            .filter_map(|batch_id| {
+                let batch = inner.batches.remove(&batch_id)?;
+                Some(batch.complete(batch_id, &inner.jobs, self.result_ttl))
+            })
+            .collect()
+    }
+
+    /// Groups the jobs under the batch. Returns the batch right away if all of its jobs
+    /// are already finished.
+    pub fn add_batch(
+        &self,
+        batch_id: Uuid,
+        callback: CallbackTarget,
+        items: Vec<Uuid>,
+    ) -> Option<CompletedBatch> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut pending = HashSet::new();
+        let mut errors = HashMap::new();
+        for id in &items {
+            match inner.jobs.get(id) {
+                Some(job) if matches!(job.status, JobStatus::Queued | JobStatus::Running) => {
+                    pending.insert(*id);
+                }
+                Some(job) => {
+                    if let Some(error) = &job.error {
+                        errors.insert(*id, error.clone());
+                    }
+                }
+                None => {}
+            }
+        }
+
+        let batch = Batch {
+            callback,
+            items,
+            pending,
+            errors,
+        };
+        if batch.pending.is_empty() {
+            return Some(batch.complete(batch_id, &inner.jobs, self.result_ttl));
+        }
+
+        inner.batches.insert(batch_id, batch);
+        None
+    }
+
+    pub fn remove(&self, id: Uuid) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(key) = inner.jobs.remove(&id).and_then(|job| job.dedup_key) {
+            if inner.keys.get(&key) == Some(&id) {
+                inner.keys.remove(&key);
+            }
+        }
+    }
+
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.inner.lock().unwrap().jobs.contains_key(&id)
+    }
+
+    /// Forgets finished jobs which results are older than their TTL. Returns the forgotten
+    /// jobs, their files should be removed.
+    pub fn remove_expired(&self) -> Vec<Uuid> {
+        let now = SystemTime::now();
+        let mut inner = self.inner.lock().unwrap();
+        let expired: Vec<Uuid> = inner
+            .jobs
+            .iter()
+            .filter(|(_, job)| {
+                matches!(job.status, JobStatus::Done | JobStatus::Failed)
+                    && now
+                        .duration_since(job.updated_at)
+                        .is_ok_and(|age| age > job.result_ttl)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in &expired {
+            if let Some(key) = inner.jobs.remove(id).and_then(|job| job.dedup_key) {
+                if inner.keys.get(&key) == Some(id) {
+                    inner.keys.remove(&key);
+                }
+            }
+        }
+        expired
+    }
+
+    fn is_reusable(&self, job: &Job) -> bool {
+        // The result may be removed before the new caller downloads it.
+        if job.delete_on_download {
+            return false;
+        }
+        match job.status {
+            JobStatus::Queued | JobStatus::Running => true,
+            JobStatus::Done => SystemTime::now()
+                .duration_since(job.updated_at)
+                .is_ok_and(|age| age < job.result_ttl),
+            JobStatus::Failed => false,
+        }
+    }
+}
+
+/// Builds the deduplication key from the input digest, normalized transcoding parameters and
+/// the result file name.
+pub fn dedup_key(input_digest: &str, params: &TaskParams) -> String {
+    let mut codec_opts: Vec<&str> = params
+        .codec_opts
+        .as_deref()
+        .unwrap_or_default()
+        .split(';')
+        .map(str::trim)
+        .filter(|opt| !opt.is_empty())
+        .collect();
+    codec_opts.sort_unstable();
+
+    let channel_layout = match params.channel_layout.as_deref().unwrap_or_default() {
+        "mono" => "mono",
+        "stereo_downmix" => "stereo_downmix",
+        _ => "stereo",
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update(input_digest.as_bytes());
+    hasher.update(
+        format!(
+            "\n{}\n{}\n{}\n{:?}\n{:?}\n{}\n{}\n{:?}",
+            params.format.trim().to_lowercase(),
+            params.codec.trim(),
+            codec_opts.join(";"),
+            params.bit_rate,
+            params.max_bit_rate,
+            params.sample_rate,
+            channel_layout,
+            // The name is sent in `Content-Disposition` of the downloads.
+            params.file_name,
+        )
+        .as_bytes(),
+    );
+    hex::encode(hasher.finalize())
+}
+
+/// Calculates SHA-256 of the file contents.
+pub fn file_digest(path: &std::path::Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dto::Headers;
+
+    fn params() -> TaskParams {
+        TaskParams {
+            format: "ogg".to_string(),
+            codec: "libopus".to_string(),
+            codec_opts: Some("application=voip;frame_duration=20".to_string()),
+            bit_rate: Some(64_000),
+            max_bit_rate: None,
+            sample_rate: 48_000,
+            channel_layout: None,
+            url: None,
+            headers: None,
+            checksum: None,
+            stream: false,
+            input_path: "in".to_string(),
+            output_path: "out".to_string(),
+            callback: None,
+            output_url: None,
+            max_body_size: 0,
+            file_name: None,
+        }
+    }
+
+    fn callback(url: &str) -> CallbackTarget {
+        CallbackTarget {
+            url: url.to_string(),
+            method: "POST",
+            headers: Headers::default(),
+        }
+    }
+
+    fn result() -> Arc<JobResult> {
+        Arc::new(JobResult {
+            size: 1,
+            mime_type: "audio/ogg".to_string(),
+            file_name: "result.ogg".to_string(),
+            duration: 1.0,
+            codec: "opus".to_string(),
+            sample_rate: 48_000,
+            channels: 2,
+            bit_rate: 64_000,
+            processing_time_ms: 1,
+        })
+    }
+
+    fn error() -> JobError {
+        JobError {
+            code: ErrorCode::TranscodingFailed,
+            message: "failed".to_string(),
+        }
+    }
+
+    fn retention(ttl: Option<u64>) -> Retention {
+        Retention {
+            ttl: ttl.map(Duration::from_secs),
+            delete_on_download: false,
+        }
+    }
+
+    fn claim(registry: &JobRegistry, key: &str, owner: Option<&str>) -> Option<Duplicate> {
+        registry.claim(
+            Uuid::new_v4(),
+            Some(key.to_string()),
+            retention(None),
+            owner.map(str::to_string),
+            None,
+        )
+    }
+
+    #[test]
+    fn dedup_key_is_normalized() {
+        let key = dedup_key("digest", &params());
+
+        let mut same = params();
+        same.format = " OGG ".to_string();
+        same.codec_opts = Some(" frame_duration=20 ; application=voip;".to_string());
+        same.channel_layout = Some("unknown".to_string());
+        same.input_path = "other".to_string();
+        assert_eq!(dedup_key("digest", &same), key);
+
+        let mut other = params();
+        other.file_name = Some("name.ogg".to_string());
+        assert_ne!(dedup_key("digest", &other), key);
+        let mut other = params();
+        other.channel_layout = Some("mono".to_string());
+        assert_ne!(dedup_key("digest", &other), key);
+        assert_ne!(dedup_key("other", &params()), key);
+    }
+
+    #[test]
+    fn claim_deduplicates_per_owner() {
+        let registry = JobRegistry::new(3600);
+        let id = Uuid::new_v4();
+        let key = Some("key".to_string());
+        assert!(registry
+            .claim(id, key.clone(), retention(None), None, None)
+            .is_none());
+
+        let duplicate = registry
+            .claim(Uuid::new_v4(), key, retention(None), None, None)
+            .unwrap();
+        assert_eq!(duplicate.id, id);
+        assert!(duplicate.done.is_none());
+
+        assert!(claim(&registry, "key", Some("billing")).is_none());
+        assert!(claim(&registry, "other", None).is_none());
+    }
+
+    #[test]
+    fn claim_attaches_callbacks() {
+        let registry = JobRegistry::new(3600);
+        let id = Uuid::new_v4();
+        let key = Some("key".to_string());
+        registry.claim(id, key.clone(), retention(None), None, None);
+
+        let duplicate = registry
+            .claim(
+                Uuid::new_v4(),
+                key.clone(),
+                retention(None),
+                None,
+                Some(callback("http://first")),
+            )
+            .unwrap();
+        assert!(duplicate.done.is_none());
+
+        registry.set_result(id, result());
+        registry.finish(id, None);
+        let callbacks = registry.take_callbacks(id);
+        assert_eq!(callbacks.len(), 1);
+        assert_eq!(callbacks[0].url, "http://first");
+        assert!(registry.take_callbacks(id).is_empty());
+
+        // The result is sent right away once the job is done
+        let duplicate = registry
+            .claim(
+                Uuid::new_v4(),
+                key,
+                retention(None),
+                None,
+                Some(callback("http://second")),
+            )
+            .unwrap();
+        let (callback, result) = duplicate.done.unwrap();
+        assert_eq!(callback.url, "http://second");
+        assert_eq!(result.file_name, "result.ogg");
+    }
+
+    #[test]
+    fn claim_extends_result_ttl() {
+        let registry = JobRegistry::new(3600);
+        let id = Uuid::new_v4();
+        let key = Some("key".to_string());
+        registry.claim(id, key.clone(), retention(Some(60)), None, None);
+        registry
+            .claim(Uuid::new_v4(), key, retention(Some(7200)), None, None)
+            .unwrap();
+
+        assert_eq!(registry.result_ttl(id), Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn result_ttl_counts_from_finish() {
+        let registry = JobRegistry::new(3600);
+        let id = Uuid::new_v4();
+        registry.claim(id, None, retention(Some(120)), None, None);
+        assert_eq!(registry.result_ttl(id), Duration::from_secs(120));
+
+        registry.finish(id, None);
+        let ttl = registry.result_ttl(id);
+        assert!(ttl <= Duration::from_secs(120) && ttl > Duration::from_secs(110));
+        assert_eq!(
+            registry.result_ttl(Uuid::new_v4()),
+            Duration::from_secs(3600)
+        );
+    }
+
+    #[test]
+    fn delete_on_download_jobs_are_not_reused() {
+        let registry = JobRegistry::new(3600);
+        let key = Some("key".to_string());
+        let once = Retention {
+            ttl: None,
+            delete_on_download: true,
+        };
+        registry.claim(Uuid::new_v4(), key.clone(), once, None, None);
+        assert!(registry
+            .claim(Uuid::new_v4(), key, retention(None), None, None)
+            .is_none());
+    }
+
+    #[test]
+    fn failed_jobs_are_not_reused() {
+        let registry = JobRegistry::new(3600);
+        let id = Uuid::new_v4();
+        registry.claim(id, Some("key".to_string()), retention(None), None, None);
+        assert!(registry.finish(id, Some(error())).is_empty());
+
+        let info = registry.get(id).unwrap();
+        assert_eq!(info.status, JobStatus::Failed);
+        assert!(claim(&registry, "key", None).is_none());
+    }
+
+    #[test]
+    fn finish_completes_batches() {
+        let registry = JobRegistry::new(3600);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        registry.claim(first, None, retention(None), None, None);
+        registry.claim(second, None, retention(None), None, None);
+
+        let batch_id = Uuid::new_v4();
+        assert!(registry
+            .add_batch(batch_id, callback("http://batch"), vec![first, second])
+            .is_none());
+        assert!(registry.finish(first, None).is_empty());
+
+        let completed = registry.finish(second, Some(error()));
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].id, batch_id);
+        assert_eq!(completed[0].items.len(), 2);
+        assert_eq!(completed[0].items[0].id, first);
+        assert!(completed[0].items[0].error.is_none());
+        assert_eq!(completed[0].items[1].id, second);
+        assert!(completed[0].items[1].error.is_some());
+    }
+
+    #[test]
+    fn add_batch_of_finished_jobs_completes_right_away() {
+        let registry = JobRegistry::new(3600);
+        let id = Uuid::new_v4();
+        registry.claim(id, None, retention(None), None, None);
+        registry.finish(id, Some(error()));
+
+        let completed = registry
+            .add_batch(Uuid::new_v4(), callback("http://batch"), vec![id])
+            .unwrap();
+        assert_eq!(completed.items.len(), 1);
+        assert_eq!(
+            completed.items[0].error.as_ref().map(|error| error.code),
+            Some(ErrorCode::TranscodingFailed)
+        );
+    }
+}
@@ -1,91 +1,349 @@
 use std::env;
-use std::ffi::OsStr;
-use std::time::{Duration, SystemTime};
+use std::future::IntoFuture;
+use std::time::{Duration, Instant, SystemTime};
 
-use axum::extract::{DefaultBodyLimit, Path, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::extract::{DefaultBodyLimit, Path, Query, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
-use axum::{Json, Router};
+use axum::{Extension, Json, Router};
 use axum_typed_multipart::TypedMultipart;
-use tokio::fs;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
 use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::oneshot;
 use tokio::time::interval;
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
+use url::Url;
 use uuid::Uuid;
 
-use crate::dto::{ConvertRequest, ConvertResponse, ConvertURLRequest, ErrorResponse};
-use crate::task::{Task, TaskParams};
+use crate::auth::{ApiKey, ApiKeys, Caller};
+use crate::callback::CallbackTarget;
+use crate::cleanup::Cleaner;
+use crate::disk::DiskGuard;
+use crate::dto::{
+    CallbackDelivery, ConvertBatchRequest, ConvertBatchResponse, ConvertRequest, ConvertResponse,
+    ConvertURLRequest, DeadLetterResponse, DownloadQuery, ErrorCode, ErrorResponse, Headers,
+    JobStatusResponse, UsageResponse,
+};
+use crate::fetcher::Fetcher;
+use crate::metrics::Metrics;
+use crate::registry::{dedup_key, file_digest, Duplicate, JobStatus, Retention};
+use crate::signing::constant_time_eq;
+use crate::task::{log_delivery, Task, TaskContext, TaskParams};
 use crate::thread_pool::ThreadPool;
 
 use crate::filepath;
 use crate::filepath::{in_file_path, out_file_path};
 use axum::body::Body;
 use axum::body::Bytes;
-use futures_util::StreamExt;
+use futures_util::{stream, Stream};
+use std::io::{Cursor, Read};
 use std::sync::Arc;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tokio_util::io::ReaderStream;
+use tokio::sync::mpsc;
 
 const CONTENT_LENGTH_LIMIT: usize = 1024 * 1024 * 1024; // 1GB
+const MAX_BATCH_SIZE: usize = 1000;
+const API_KEY_HEADER: &str = "X-API-Key";
+const MIN_RESULT_TTL: u64 = 60;
+const MAX_RESULT_TTL: u64 = 7 * 24 * 3600;
 
 pub struct Server {
     thread_pool: Arc<ThreadPool>,
+    fetcher: Arc<Fetcher>,
+    context: Arc<TaskContext>,
     max_body_size: usize,
     work_dir: String,
+    admin_token: Option<String>,
+    disk: Arc<DiskGuard>,
+    metrics: Arc<Metrics>,
+    api_keys: ApiKeys,
 }
 
 impl Server {
-    pub(crate) fn new(thread_pool: ThreadPool, work_dir: String) -> Server {
+    pub(crate) fn new(
+        thread_pool: Arc<ThreadPool>,
+        fetcher: Arc<Fetcher>,
+        context: Arc<TaskContext>,
+        work_dir: String,
+        api_keys: ApiKeys,
+    ) -> Server {
         Server {
-            thread_pool: Arc::new(thread_pool),
+            thread_pool,
+            fetcher,
+            context,
             max_body_size: env::var("MAX_BODY_SIZE").map_or(CONTENT_LENGTH_LIMIT, |val| {
                 val.parse().map_or(CONTENT_LENGTH_LIMIT, |val| val)
             }),
+            disk: Arc::new(DiskGuard::new(
+                &work_dir,
+                env::var("DISK_QUOTA").ok().and_then(|val| val.parse().ok()),
+                env::var("MIN_FREE_SPACE")
+                    .ok()
+                    .and_then(|val| val.parse().ok()),
+            )),
+            metrics: Arc::new(Metrics::default()),
+            api_keys,
             work_dir,
+            admin_token: env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
         }
     }
 
     pub fn start_cleanup_task(self, ttl: u64) -> Self {
-        let dir_path = self.work_dir.clone();
+        let cleaner = Arc::new(Cleaner::new(
+            Arc::clone(&self.context),
+            Arc::clone(&self.metrics),
+            &self.work_dir,
+            Duration::from_secs(ttl),
+        ));
         tokio::spawn(async move {
             let mut interval = interval(Duration::from_secs(60));
             loop {
                 interval.tick().await;
-
-                if let Err(err) = cleanup_directory(dir_path.as_str(), ttl).await {
-                    error!("could not perform working directory cleanup: {}", err);
+                let cleaner = Arc::clone(&cleaner);
+                if let Err(err) = tokio::task::spawn_blocking(move || cleaner.run()).await {
+                    error!("cleanup task failed: {}", err);
                 }
             }
         });
         self
     }
 
-    pub async fn serve(self, addr: &str) -> std::io::Result<()> {
+    /// Serves until the shutdown signal. Returns the deadline for the shutdown, which is the
+    /// grace period after the signal.
+    pub async fn serve(self, addr: &str, grace_period: Duration) -> std::io::Result<Instant> {
         let this = Arc::new(self);
-        let app = Router::new()
+        // Job routes require an API key if the keys are configured
+        let jobs = Router::new()
             .route(
                 "/enqueue",
                 post(enqueue_file).layer(DefaultBodyLimit::max(this.max_body_size)),
             )
             .route("/enqueue_url", post(enqueue_url))
-            .route("/get/:identifier", get(download_file))
+            .route("/enqueue_batch", post(enqueue_batch))
+            .route("/get/:identifier", get(download_file).delete(delete_result))
+            .route("/status/:identifier", get(job_status))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::clone(&this),
+                authenticate,
+            ));
+        let app = Router::new()
+            .merge(jobs)
+            .route("/usage", get(disk_usage))
+            .route("/metrics", get(metrics))
+            .route("/admin/dead_letters", get(list_dead_letters))
+            .route(
+                "/admin/dead_letters/:identifier/replay",
+                post(replay_dead_letter),
+            )
             .with_state(this)
             .layer(TraceLayer::new_for_http())
             .fallback(handler_not_found);
 
         tracing::info!("listening on {addr}");
         let listener = TcpListener::bind(addr).await?;
-        axum::serve(listener, app).await
+        let (signalled, signal) = oneshot::channel();
+        let serve = axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                shutdown_signal().await;
+                let _ = signalled.send(Instant::now());
+            })
+            .into_future();
+        tokio::pin!(serve);
+
+        let signalled_at = tokio::select! {
+            result = &mut serve => {
+                result?;
+                Instant::now()
+            }
+            signalled_at = signal => signalled_at.unwrap_or_else(|_| Instant::now()),
+        };
+
+        // Open connections (e.g. slow downloads) are waited for within the grace period only
+        let deadline = signalled_at + grace_period;
+        if tokio::time::timeout_at(deadline.into(), serve)
+            .await
+            .is_err()
+        {
+            warn!("open connections were not closed within the grace period");
+        }
+        Ok(deadline)
     }
 }
 
 async fn enqueue_url(
     State(server): State<Arc<Server>>,
+    caller: Option<Extension<Caller>>,
     Json(req): Json<ConvertURLRequest>,
 ) -> (StatusCode, Json<ConvertResponse>) {
+    if let Err(response) = check_disk(&server, 0).await {
+        return response;
+    }
+    enqueue_url_request(&server, req, caller.as_deref().map(|Caller(key)| key))
+}
+
+async fn enqueue_batch(
+    State(server): State<Arc<Server>>,
+    caller: Option<Extension<Caller>>,
+    Json(req): Json<ConvertBatchRequest>,
+) -> Response {
+    if req.items.is_empty() || req.items.len() > MAX_BATCH_SIZE {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json::from(ConvertBatchResponse {
+                batch_id: None,
+                items: vec![ConvertResponse {
+                    id: None,
+                    error: Some(format!(
+                        "Batch must contain from 1 to {} items",
+                        MAX_BATCH_SIZE
+                    )),
+                    ..Default::default()
+                }],
+            }),
+        )
+            .into_response();
+    }
+
+    // Every item is charged to the rate limit, the request itself has taken one token already.
+    if let Some(Extension(Caller(key))) = &caller {
+        let rejected = if key
+            .rate_limit()
+            .is_some_and(|limit| req.items.len() > limit as usize)
+        {
+            Some(("Batch exceeds the rate limit of the API key", None))
+        } else {
+            server
+                .api_keys
+                .acquire(key, req.items.len() as u32 - 1)
+                .err()
+                .map(|wait| ("Rate limit exceeded", Some(wait)))
+        };
+        if let Some((msg, wait)) = rejected {
+            let response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json::from(ConvertBatchResponse {
+                    batch_id: None,
+                    items: vec![ConvertResponse {
+                        id: None,
+                        error: Some(msg.to_string()),
+                        ..Default::default()
+                    }],
+                }),
+            )
+                .into_response();
+            return match wait {
+                Some(wait) => retry_after(response, wait),
+                None => response,
+            };
+        }
+    }
+
+    let callback = match CallbackTarget::from_request(
+        req.callback_url,
+        req.callback_method.as_deref(),
+        req.callback_headers,
+    ) {
+        Ok(callback) => callback,
+        Err(err) => {
+            let (status, Json(response)) = bad_request_response(&err);
+            return (
+                status,
+                Json::from(ConvertBatchResponse {
+                    batch_id: None,
+                    items: vec![response],
+                }),
+            )
+                .into_response();
+        }
+    };
+
+    let rejected = match check_disk(&server, 0).await {
+        Err(response) => Some(response),
+        Ok(()) if server.fetcher.is_closed() => Some(unavailable_response()),
+        Ok(()) => None,
+    };
+    if let Some((status, Json(response))) = rejected {
+        return (
+            status,
+            Json::from(ConvertBatchResponse {
+                batch_id: None,
+                items: vec![response],
+            }),
+        )
+            .into_response();
+    }
+
+    let mut job_ids = Vec::new();
+    let items: Vec<ConvertResponse> = req
+        .items
+        .into_iter()
+        .map(|item| {
+            let (status, Json(response)) =
+                enqueue_url_request(&server, item, caller.as_deref().map(|Caller(key)| key));
+            if status.is_success() {
+                if let Some(id) = response.id.as_deref().and_then(|id| id.parse().ok()) {
+                    job_ids.push(id);
+                }
+            }
+            response
+        })
+        .collect();
+
+    let status = if job_ids.is_empty() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::CREATED
+    };
+
+    let batch_id = match callback {
+        Some(callback) if !job_ids.is_empty() => {
+            let batch_id = Uuid::new_v4();
+            if let Some(batch) = server
+                .context
+                .registry
+                .add_batch(batch_id, callback, job_ids)
+            {
+                let context = Arc::clone(&server.context);
+                tokio::task::spawn_blocking(move || context.notifier.send_batch(&batch));
+            }
+            Some(batch_id.to_string())
+        }
+        _ => None,
+    };
+
+    (status, Json::from(ConvertBatchResponse { batch_id, items })).into_response()
+}
+
+fn enqueue_url_request(
+    server: &Server,
+    req: ConvertURLRequest,
+    caller: Option<&Arc<ApiKey>>,
+) -> (StatusCode, Json<ConvertResponse>) {
+    if let Err(err) = validate_url_request(server, &req) {
+        return bad_request_response(&err);
+    }
+    if caller.is_some_and(|caller| !caller.allows_codec(&req.codec)) {
+        return codec_forbidden_response(&req.codec);
+    }
+
+    let callback = match CallbackTarget::from_request(
+        req.callback_url,
+        req.callback_method.as_deref(),
+        req.callback_headers,
+    ) {
+        Ok(callback) => callback,
+        Err(err) => return bad_request_response(&err),
+    };
+    let retention = match parse_retention(req.result_ttl, req.delete_after_download) {
+        Ok(retention) => retention,
+        Err(err) => return bad_request_response(&err),
+    };
+
     let task_id = Uuid::new_v4();
     let input = in_file_path(&server.work_dir, task_id.to_string());
     let output = out_file_path(&server.work_dir, task_id.to_string());
@@ -107,36 +365,227 @@ async fn enqueue_url(
         max_bit_rate: req.max_bit_rate,
         sample_rate: req.sample_rate,
         channel_layout: req.channel_layout,
-        callback_url: req.callback_url,
+        callback,
+        output_url: req.output_url.filter(|url| !url.is_empty()),
         input_path: input_path.to_string(),
         output_path: output_path.to_string(),
-        url: Some(req.url),
-        max_body_size: server.max_body_size,
+        url: Some(req.url.clone()),
+        headers: req.headers,
+        checksum: req
+            .checksum
+            .as_deref()
+            .and_then(parse_checksum)
+            .map(str::to_lowercase),
+        stream: req.stream.unwrap_or_default(),
+        max_body_size: max_body_size(server, caller),
+        file_name: req
+            .file_name
+            .as_deref()
+            .and_then(filepath::sanitize_file_name)
+            .or_else(|| url_file_stem(&req.url)),
+    };
+
+    if server.fetcher.is_closed() {
+        return unavailable_response();
+    }
+
+    // Jobs with the output URL are never reused, the result must be uploaded to every URL.
+    // The contents behind the URL may change, so only the inputs with a checksum are reused.
+    let dedup_key = match &params.checksum {
+        Some(checksum)
+            if !req.no_dedup.unwrap_or_default()
+                && params.output_url.is_none()
+                && !retention.delete_on_download =>
+        {
+            // Different headers may give access to different contents, so they are a part of
+            // the key too.
+            let headers = params
+                .headers
+                .as_ref()
+                .map(Headers::canonical)
+                .unwrap_or_default();
+            Some(dedup_key(
+                &format!("url:{}\n{}\n{}", req.url, headers, checksum),
+                &params,
+            ))
+        }
+        _ => None,
     };
-    let task = Task::new(task_id, params);
+    let owner = caller.map(|caller| caller.name.clone());
+    if let Some(duplicate) = server.context.registry.claim(
+        task_id,
+        dedup_key,
+        retention,
+        owner,
+        params.callback.clone(),
+    ) {
+        return deduplicated_response(&server.context, duplicate);
+    }
 
-    // Enqueue the task to the thread pool
-    server.thread_pool.enqueue(task);
+    let stream = params.stream;
+    let task = Task::new(task_id, params, Arc::clone(&server.context));
+    if stream {
+        // Streamed inputs are downloaded by the worker while transcoding
+        if server.thread_pool.enqueue(task).is_err() {
+            server.context.registry.remove(task_id);
+            return unavailable_response();
+        }
+    } else {
+        // Download the input first, the task will be sent to the thread pool afterwards
+        server.fetcher.enqueue(task);
+    }
 
     (
         StatusCode::CREATED,
         Json::from(ConvertResponse {
             id: Some(task_id.to_string()),
             error: None,
+            ..Default::default()
         }),
     )
 }
 
+fn validate_url_request(server: &Server, req: &ConvertURLRequest) -> Result<(), String> {
+    if req.format.trim().is_empty() {
+        return Err("format is required".to_string());
+    }
+    if req.codec.trim().is_empty() {
+        return Err("codec is required".to_string());
+    }
+    if req.sample_rate < 0 {
+        return Err("sample_rate must not be negative".to_string());
+    }
+    if let Err(err) = server.fetcher.check_url(&req.url) {
+        return Err(format!("url is not allowed: {}", err));
+    }
+    if let Some(headers) = &req.headers {
+        headers.validate_download()?;
+    }
+    if let Some(checksum) = &req.checksum {
+        if parse_checksum(checksum).is_none() {
+            return Err("checksum must be a SHA-256 hex digest".to_string());
+        }
+        if req.stream.unwrap_or_default() {
+            return Err("checksum can't be verified for streamed inputs".to_string());
+        }
+    }
+    validate_output_url(server, req.output_url.as_deref())
+}
+
+fn parse_retention(
+    result_ttl: Option<u64>,
+    delete_after_download: Option<bool>,
+) -> Result<Retention, String> {
+    if let Some(ttl) = result_ttl {
+        if !(MIN_RESULT_TTL..=MAX_RESULT_TTL).contains(&ttl) {
+            return Err(format!(
+                "result_ttl must be from {} to {} seconds",
+                MIN_RESULT_TTL, MAX_RESULT_TTL
+            ));
+        }
+    }
+    Ok(Retention {
+        ttl: result_ttl.map(Duration::from_secs),
+        delete_on_download: delete_after_download.unwrap_or_default(),
+    })
+}
+
+/// Name of the file in the URL without the extension.
+fn url_file_stem(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let name = url
+        .path_segments()?
+        .rev()
+        .find(|segment| !segment.is_empty())?;
+    filepath::file_stem(&percent_decode_str(name).decode_utf8_lossy())
+}
+
+/// Returns the hex digest from `sha256:<hex>` or `<hex>`.
+fn parse_checksum(checksum: &str) -> Option<&str> {
+    let hex = checksum.trim();
+    let hex = hex.strip_prefix("sha256:").unwrap_or(hex);
+    (hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())).then_some(hex)
+}
+
+fn validate_output_url(server: &Server, output_url: Option<&str>) -> Result<(), String> {
+    match output_url {
+        Some(url) if !url.starts_with("http://") && !url.starts_with("https://") => {
+            Err("output_url must be an HTTP or HTTPS URL".to_string())
+        }
+        Some(url) => server
+            .context
+            .uploader
+            .check_url(url)
+            .map(|_| ())
+            .map_err(|err| format!("output_url is not allowed: {}", err)),
+        None => Ok(()),
+    }
+}
+
 async fn enqueue_file(
     State(server): State<Arc<Server>>,
+    caller: Option<Extension<Caller>>,
     TypedMultipart(req): TypedMultipart<ConvertRequest>,
 ) -> (StatusCode, Json<ConvertResponse>) {
+    let caller = caller.map(|Extension(Caller(key))| key);
+    if let Err(err) = validate_output_url(&server, req.output_url.as_deref()) {
+        return bad_request_response(&err);
+    }
+    if caller
+        .as_ref()
+        .is_some_and(|caller| !caller.allows_codec(&req.codec))
+    {
+        return codec_forbidden_response(&req.codec);
+    }
+
+    let callback_headers = match req.callback_headers.as_deref() {
+        Some(headers) if !headers.trim().is_empty() => {
+            match serde_json::from_str::<Headers>(headers) {
+                Ok(headers) => Some(headers),
+                Err(_) => return bad_request_response("callback_headers must be a JSON object"),
+            }
+        }
+        _ => None,
+    };
+    let callback = match CallbackTarget::from_request(
+        req.callback_url,
+        req.callback_method.as_deref(),
+        callback_headers,
+    ) {
+        Ok(callback) => callback,
+        Err(err) => return bad_request_response(&err),
+    };
+    let retention = match parse_retention(req.result_ttl, req.delete_after_download) {
+        Ok(retention) => retention,
+        Err(err) => return bad_request_response(&err),
+    };
+
+    let file = req.file;
+    let size = file
+        .contents
+        .as_file()
+        .metadata()
+        .map_or(0, |metadata| metadata.len());
+    // Chunked uploads have no Content-Length to be checked by the middleware
+    if size > max_body_size(&server, caller.as_ref()) as u64 {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json::from(ConvertResponse {
+                id: None,
+                error: Some("File exceeds the upload size limit".to_string()),
+                error_code: Some(ErrorCode::InputTooLarge),
+                ..Default::default()
+            }),
+        );
+    }
+    if let Err(response) = check_disk(&server, size).await {
+        return response;
+    }
+
     let task_id = Uuid::new_v4();
     let input = in_file_path(&server.work_dir, task_id.to_string());
     let output = out_file_path(&server.work_dir, task_id.to_string());
 
-    let file = req.file;
-
     match file.contents.persist(input.clone()) {
         Ok(_) => {
             let input_path = match input.to_str() {
@@ -156,73 +605,689 @@ async fn enqueue_file(
                 max_bit_rate: req.max_bit_rate,
                 sample_rate: req.sample_rate,
                 channel_layout: req.channel_layout,
-                callback_url: req.callback_url,
+                callback,
+                output_url: req.output_url.filter(|url| !url.is_empty()),
                 input_path: input_path.to_string(),
                 output_path: output_path.to_string(),
                 url: None,
-                max_body_size: server.max_body_size,
+                headers: None,
+                checksum: None,
+                stream: false,
+                max_body_size: max_body_size(&server, caller.as_ref()),
+                file_name: req
+                    .file_name
+                    .as_deref()
+                    .and_then(filepath::sanitize_file_name)
+                    .or_else(|| {
+                        file.metadata
+                            .file_name
+                            .as_deref()
+                            .and_then(filepath::file_stem)
+                    }),
+            };
+
+            let dedup_key = if req.no_dedup.unwrap_or_default()
+                || params.output_url.is_some()
+                || retention.delete_on_download
+            {
+                None
+            } else {
+                let path = input.clone();
+                match tokio::task::spawn_blocking(move || file_digest(&path)).await {
+                    Ok(Ok(digest)) => Some(dedup_key(&format!("file:{}", digest), &params)),
+                    _ => {
+                        std::fs::remove_file(&input).ok();
+                        return error_response("Cannot read the file");
+                    }
+                }
             };
-            let task = Task::new(task_id, params);
+            let owner = caller.as_ref().map(|caller| caller.name.clone());
+            if let Some(duplicate) = server.context.registry.claim(
+                task_id,
+                dedup_key,
+                retention,
+                owner,
+                params.callback.clone(),
+            ) {
+                std::fs::remove_file(&input).ok();
+                return deduplicated_response(&server.context, duplicate);
+            }
+
+            let task = Task::new(task_id, params, Arc::clone(&server.context));
+            let stored = {
+                let task = task.clone();
+                tokio::task::spawn_blocking(move || task.store_input()).await
+            };
+            if !matches!(stored, Ok(Ok(()))) {
+                std::fs::remove_file(&input).ok();
+                server.context.registry.remove(task_id);
+                return error_response("Cannot save the file");
+            }
 
             // Enqueue the task to the thread pool
-            server.thread_pool.enqueue(task);
+            if let Err(err) = server.thread_pool.enqueue(task) {
+                tokio::task::spawn_blocking(move || err.0.remove_input());
+                server.context.registry.remove(task_id);
+                return unavailable_response();
+            }
 
             (
                 StatusCode::CREATED,
                 Json::from(ConvertResponse {
                     id: Some(task_id.to_string()),
                     error: None,
+                    ..Default::default()
                 }),
             )
         }
-        Err(_) => error_response("Cannot save the file"),
+        Err(err) if err.error.raw_os_error() == Some(libc::ENOSPC) => (
+            StatusCode::INSUFFICIENT_STORAGE,
+            Json::from(ConvertResponse {
+                id: None,
+                error: Some("Cannot save the file: no space left on device".to_string()),
+                error_code: Some(ErrorCode::LowDiskSpace),
+                ..Default::default()
+            }),
+        ),
+        Err(err) => {
+            error!("cannot save the file: {}", err);
+            error_response("Cannot save the file")
+        }
     }
 }
 
 async fn download_file(
     State(server): State<Arc<Server>>,
+    method: Method,
+    headers: HeaderMap,
     Path(identifier): Path<String>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let file_path = out_file_path(&server.work_dir, identifier);
+    Query(link): Query<DownloadQuery>,
+    caller: Option<Extension<Caller>>,
+) -> Result<Response<Body>, StatusCode> {
+    let id: Uuid = identifier.parse().map_err(|_| StatusCode::NOT_FOUND)?;
+    authorize_download(&server, &Method::GET, id, &link, caller.as_deref())?;
+    let storage = Arc::clone(&server.context.storage);
+    let key = filepath::out_file_name(&id.to_string());
+
+    // The storage is blocking, the first bytes are read to guess the MIME type
+    let opened = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+        let Some(mut object) = storage.open(&key, 0)? else {
+            return Ok(None);
+        };
+        let mut head = Vec::with_capacity(512);
+        object.reader.by_ref().take(512).read_to_end(&mut head)?;
+        Ok(Some((head, object)))
+    })
+    .await;
+
+    let (head, object) = match opened {
+        Ok(Ok(Some(opened))) => opened,
+        Ok(Ok(None)) => return Err(StatusCode::NOT_FOUND),
+        _ => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    // Empty results are never stored, and the size must be right to serve the ranges
+    if head.is_empty() || head.len() as u64 > object.size {
+        error!("result {} has a wrong size: {} bytes", id, object.size);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // The type is kept with the result, it's guessed only for the results stored without it
+    let job = server.context.registry.get(id);
+    let mime_type = job
+        .as_ref()
+        .and_then(|job| job.mime_type.clone())
+        .or_else(|| object.meta.content_type.clone())
+        .unwrap_or_else(|| filepath::guess_mime_type(&head));
+    let file_name = job
+        .as_ref()
+        .and_then(|job| job.file_name.clone())
+        .or_else(|| object.meta.file_name.clone())
+        .unwrap_or_else(|| id.to_string());
 
-    if !file_path.exists() {
-        return Err(StatusCode::NOT_FOUND);
+    let size = object.size;
+    let etag = entity_tag(size, object.modified);
+    let last_modified = httpdate::fmt_http_date(object.modified);
+    let mut response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, &last_modified)
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    if is_not_modified(&headers, &etag, object.modified) {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
     }
 
-    let mut file = match File::open(&file_path).await {
-        Ok(file) => file,
-        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    let if_range = headers
+        .get(header::IF_RANGE)
+        .and_then(|value| value.to_str().ok());
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| is_range_fresh(if_range, &etag, &last_modified))
+        .and_then(|value| parse_range(value, size));
+    let (start, end) = match range {
+        Some(Ok((start, end))) => {
+            response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, size),
+            );
+            (start, end)
+        }
+        Some(Err(())) => {
+            return response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .body(Body::empty())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        None => (0, size - 1),
     };
+    let len = end - start + 1;
+    response = response
+        .header(header::CONTENT_TYPE, mime_type)
+        .header(header::CONTENT_DISPOSITION, content_disposition(&file_name))
+        .header(header::CONTENT_LENGTH, len);
 
-    let mut buffer = [0; 512];
-    let n = match file.read(&mut buffer).await {
-        Ok(n) if n > 0 => n,
-        _ => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    if method == Method::HEAD {
+        return response
+            .body(Body::empty())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let reader: Box<dyn Read + Send> = if start == 0 {
+        Box::new(Cursor::new(head).chain(object.reader).take(len))
+    } else {
+        drop(object);
+        let storage = Arc::clone(&server.context.storage);
+        let key = filepath::out_file_name(&id.to_string());
+        match tokio::task::spawn_blocking(move || storage.open(&key, start)).await {
+            Ok(Ok(Some(object))) => Box::new(object.reader.take(len)),
+            Ok(Ok(None)) => return Err(StatusCode::NOT_FOUND),
+            _ => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
     };
 
-    let mime_type = infer::get(&buffer[..n]).map_or("application/octet-stream".to_string(), |t| {
-        t.mime_type().to_string()
+    // Only the download of the whole file counts, parts may be requested again
+    let delete_on_download = len == size && job.is_some_and(|job| job.delete_on_download);
+    let context = Arc::clone(&server.context);
+    let body = Body::from_stream(blocking_stream(reader, move || {
+        if delete_on_download {
+            debug!("result {} has been downloaded, removing it", id);
+            remove_result(&context, id);
+        }
+    }));
+
+    response
+        .body(body)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Asks to save the result with the file name. Non-ASCII names are sent in `filename*`,
+/// `filename` contains the ASCII fallback for the old clients.
+fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| if c.is_ascii() && c != '\\' { c } else { '_' })
+        .collect();
+    if fallback == file_name {
+        return format!("attachment; filename=\"{}\"", file_name);
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        utf8_percent_encode(file_name, NON_ALPHANUMERIC)
+    )
+}
+
+/// Results are never modified, so the size and the modification time identify the contents.
+fn entity_tag(size: u64, modified: SystemTime) -> String {
+    let modified = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    format!("\"{:x}-{:x}\"", size, modified)
+}
+
+/// Checks `If-None-Match`, or `If-Modified-Since` if there's no `If-None-Match`.
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+        return value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        });
+    }
+
+    // HTTP dates have a precision of one second
+    let secs = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs())
+    };
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .is_some_and(|since| secs(modified) <= secs(since))
+}
+
+/// The range is ignored if the file has changed since the client got its part. `If-Range`
+/// requires the strong comparison, so weak ETags never match.
+fn is_range_fresh(if_range: Option<&str>, etag: &str, last_modified: &str) -> bool {
+    match if_range.map(str::trim) {
+        Some(value) => value == etag || value == last_modified,
+        None => true,
+    }
+}
+
+/// Parses a single `bytes` range into the inclusive bounds, `Err` means the range can't be
+/// satisfied. `None` means the whole file should be sent, which is also the case for
+/// multiple ranges since they are not supported.
+fn parse_range(value: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if size == 0 {
+        // No range can be satisfied, the syntax is still checked
+        return (start.parse::<u64>().is_ok() || end.parse::<u64>().is_ok()).then_some(Err(()));
+    }
+
+    if start.is_empty() {
+        // Suffix range, the last N bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((size.saturating_sub(suffix), size - 1)));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end = if end.is_empty() {
+        None
+    } else {
+        Some(end.parse::<u64>().ok()?)
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    if start >= size {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end.map_or(size - 1, |end| end.min(size - 1)))))
+}
+
+/// Removes the job result from the storage, the job is forgotten.
+async fn delete_result(
+    State(server): State<Arc<Server>>,
+    Path(identifier): Path<String>,
+    Query(link): Query<DownloadQuery>,
+    caller: Option<Extension<Caller>>,
+) -> StatusCode {
+    let Ok(id) = identifier.parse::<Uuid>() else {
+        return StatusCode::NOT_FOUND;
+    };
+    if let Err(status) = authorize_download(&server, &Method::DELETE, id, &link, caller.as_deref())
+    {
+        return status;
+    }
+    if server
+        .context
+        .registry
+        .get(id)
+        .is_some_and(|job| matches!(job.status, JobStatus::Queued | JobStatus::Running))
+    {
+        return StatusCode::CONFLICT;
+    }
+
+    let context = Arc::clone(&server.context);
+    let removed = tokio::task::spawn_blocking(move || {
+        let key = filepath::out_file_name(&id.to_string());
+        match context.storage.exists(&key) {
+            Ok(true) if remove_result(&context, id) => StatusCode::NO_CONTENT,
+            Ok(true) => StatusCode::INTERNAL_SERVER_ERROR,
+            Ok(false) => StatusCode::NOT_FOUND,
+            Err(err) => {
+                error!("couldn't check the result {}: {}", id, err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    })
+    .await;
+    removed.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Removes the result from the storage and forgets the job. Returns `false` if the result
+/// couldn't be removed.
+fn remove_result(context: &TaskContext, id: Uuid) -> bool {
+    context.registry.remove(id);
+    match context
+        .storage
+        .delete(&filepath::out_file_name(&id.to_string()))
+    {
+        Ok(()) => true,
+        Err(err) => {
+            error!("couldn't remove the result {}: {}", id, err);
+            false
+        }
+    }
+}
+
+/// Reads the blocking reader on the blocking thread pool and streams its contents.
+/// `on_complete` is called after the whole contents have been sent.
+fn blocking_stream(
+    mut reader: impl Read + Send + 'static,
+    on_complete: impl FnOnce() + Send + 'static,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let item = match reader.read(&mut buffer) {
+                Ok(0) => {
+                    on_complete();
+                    break;
+                }
+                Ok(n) => Ok(Bytes::copy_from_slice(&buffer[..n])),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = item.is_err();
+            // The receiver is dropped if the client has disconnected
+            if tx.blocking_send(item).is_err() || failed {
+                break;
+            }
+        }
     });
 
-    let file = match File::open(&file_path).await {
-        Ok(file) => file,
-        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })
+}
+
+async fn job_status(
+    State(server): State<Arc<Server>>,
+    Path(identifier): Path<String>,
+    caller: Option<Extension<Caller>>,
+) -> Result<Json<JobStatusResponse>, StatusCode> {
+    let id = identifier.parse().map_err(|_| StatusCode::NOT_FOUND)?;
+    check_owner(&server, id, caller.as_deref())?;
+    let job = server
+        .context
+        .registry
+        .get(id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json::from(JobStatusResponse {
+        id: identifier,
+        status: job.status,
+        error: job.error.as_ref().map(|error| error.message.clone()),
+        error_code: job.error.map(|error| error.code),
+        callback: job.callback,
+    }))
+}
+
+async fn disk_usage(State(server): State<Arc<Server>>) -> Result<Json<UsageResponse>, StatusCode> {
+    let disk = Arc::clone(&server.disk);
+    match tokio::task::spawn_blocking(move || disk.usage()).await {
+        Ok(Ok(usage)) => Ok(Json::from(usage)),
+        Ok(Err(err)) => {
+            error!("couldn't calculate disk usage: {}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn metrics(State(server): State<Arc<Server>>) -> impl IntoResponse {
+    (
+        [(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        server.metrics.render(),
+    )
+}
+
+async fn list_dead_letters(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DeadLetterResponse>>, StatusCode> {
+    authorize_admin(&server, &headers)?;
+    let store = server
+        .context
+        .notifier
+        .dead_letters()
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let letters = store.list().map_err(|err| {
+        error!("couldn't list dead letters: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json::from(
+        letters
+            .into_iter()
+            .map(|letter| DeadLetterResponse {
+                id: letter.id.to_string(),
+                subject: letter.subject.to_string(),
+                url: letter.url,
+                method: letter.method,
+                headers: letter.headers.redacted(),
+                body: letter.body,
+                attempts: letter.attempts,
+                error: letter.error,
+                failed_at: letter.failed_at,
+            })
+            .collect::<Vec<_>>(),
+    ))
+}
+
+async fn replay_dead_letter(
+    State(server): State<Arc<Server>>,
+    Path(identifier): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<CallbackDelivery>, StatusCode> {
+    authorize_admin(&server, &headers)?;
+    let id = identifier.parse().map_err(|_| StatusCode::NOT_FOUND)?;
+    let store = server
+        .context
+        .notifier
+        .dead_letters()
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let letter = match store.get(id) {
+        Ok(Some(letter)) => letter,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            error!("couldn't read dead letter id={}: {}", id, err);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
     };
 
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream.map(|result| match result {
-        Ok(bytes) => Ok(Bytes::from(bytes)),
-        Err(err) => Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            err.to_string(),
-        )),
-    }));
+    let context = Arc::clone(&server.context);
+    let subject = letter.subject;
+    let delivery = tokio::task::spawn_blocking(move || context.notifier.replay(letter))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|err| {
+            error!("couldn't replay dead letter id={}: {}", id, err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    server
+        .context
+        .registry
+        .set_callback_delivery(subject, delivery.clone());
+    Ok(Json::from(delivery))
+}
 
-    Ok(([(http::header::CONTENT_TYPE, mime_type)], body))
+/// Authenticates the API key from `X-API-Key` or `Authorization: Bearer` and applies its
+/// limits. Downloads may be authorized with a signed link instead, so they are let through
+/// without a key to be checked by the handler.
+async fn authenticate(State(server): State<Arc<Server>>, mut req: Request, next: Next) -> Response {
+    match check_api_key(&server.api_keys, req.headers(), req.uri().path()) {
+        Ok(Some(key)) => {
+            req.extensions_mut().insert(Caller(key));
+            next.run(req).await
+        }
+        Ok(None) => next.run(req).await,
+        Err((status, msg, None)) => auth_error_response(status, msg),
+        Err((status, msg, Some(wait))) => retry_after(auth_error_response(status, msg), wait),
+    }
+}
+
+/// Rejected request: the status, the message and how long to wait before retrying.
+type AuthError = (StatusCode, &'static str, Option<Duration>);
+
+/// Returns the caller's key, or `None` if the request may go without a key.
+fn check_api_key(
+    api_keys: &ApiKeys,
+    headers: &HeaderMap,
+    path: &str,
+) -> Result<Option<Arc<ApiKey>>, AuthError> {
+    if !api_keys.is_enabled() {
+        return Ok(None);
+    }
+
+    let provided = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        });
+    let key = match provided.and_then(|provided| api_keys.find(provided)) {
+        Some(key) => key,
+        None if provided.is_none() && path.starts_with("/get/") => return Ok(None),
+        None => return Err((StatusCode::UNAUTHORIZED, "Invalid API key", None)),
+    };
+
+    if let Err(wait) = api_keys.acquire(&key, 1) {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Rate limit exceeded",
+            Some(wait),
+        ));
+    }
+
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if let (Some(length), Some(max)) = (content_length, key.max_upload_size) {
+        if length > max {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request exceeds the upload size limit",
+                None,
+            ));
+        }
+    }
+
+    Ok(Some(key))
+}
+
+fn retry_after(mut response: Response, wait: Duration) -> Response {
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(wait.as_secs_f64().ceil() as u64),
+    );
+    response
+}
+
+fn auth_error_response(status: StatusCode, msg: &str) -> Response {
+    (
+        status,
+        Json::from(ErrorResponse {
+            error: msg.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+/// The smaller of the server and the API key limits.
+fn max_body_size(server: &Server, caller: Option<&Arc<ApiKey>>) -> usize {
+    caller
+        .and_then(|caller| caller.max_upload_size)
+        .map_or(server.max_body_size, |max| max.min(server.max_body_size))
+}
+
+fn codec_forbidden_response(codec: &str) -> (StatusCode, Json<ConvertResponse>) {
+    (
+        StatusCode::FORBIDDEN,
+        Json::from(ConvertResponse {
+            id: None,
+            error: Some(format!("codec {} is not allowed for this API key", codec)),
+            ..Default::default()
+        }),
+    )
+}
+
+/// With the API keys enabled, jobs are visible only to the key which created them. The owner
+/// is known only to the instance which accepted the job, jobs unknown to this instance (e.g.
+/// the results shared through S3) can't be checked, so they are not visible either.
+fn check_owner(server: &Server, id: Uuid, caller: Option<&Caller>) -> Result<(), StatusCode> {
+    if !server.api_keys.is_enabled() {
+        return Ok(());
+    }
+    let Some(Caller(key)) = caller else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    match server.context.registry.get(id) {
+        Some(job) if job.owner.as_deref() == Some(key.name.as_str()) => Ok(()),
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Checks the signature of the link if the links must be signed, `HEAD` uses the links
+/// signed for `GET`. The owner of the job may use the API key instead.
+fn authorize_download(
+    server: &Server,
+    method: &Method,
+    id: Uuid,
+    link: &DownloadQuery,
+    caller: Option<&Caller>,
+) -> Result<(), StatusCode> {
+    if let Some(signer) = server.context.notifier.link_signer() {
+        if let (Some(expires), Some(signature)) = (link.expires, link.signature.as_deref()) {
+            if signer.verify(method.as_str(), id, expires, signature) {
+                return Ok(());
+            }
+        }
+        if !server.api_keys.is_enabled() || caller.is_none() {
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+    check_owner(server, id, caller)
+}
+
+/// Admin endpoints are disabled unless `ADMIN_TOKEN` is set, the token must be sent
+/// as `Authorization: Bearer <token>`.
+fn authorize_admin(server: &Server, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let token = server.admin_token.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let provided = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
 }
 
 async fn handler_not_found() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, Json::from(ErrorResponse { error: "not found".to_string() }))
+    (
+        StatusCode::NOT_FOUND,
+        Json::from(ErrorResponse {
+            error: "not found".to_string(),
+        }),
+    )
 }
 
 fn error_response(msg: &str) -> (StatusCode, Json<ConvertResponse>) {
@@ -231,48 +1296,289 @@ fn error_response(msg: &str) -> (StatusCode, Json<ConvertResponse>) {
         Json::from(ConvertResponse {
             id: None,
             error: Some(msg.to_string()),
+            ..Default::default()
         }),
     )
 }
 
-async fn cleanup_directory(dir_path: &str, ttl: u64) -> Result<(), Box<dyn std::error::Error>> {
-    // Get the current time
-    let now = SystemTime::now();
+fn bad_request_response(msg: &str) -> (StatusCode, Json<ConvertResponse>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json::from(ConvertResponse {
+            id: None,
+            error: Some(msg.to_string()),
+            ..Default::default()
+        }),
+    )
+}
 
-    // Read the directory
-    let mut entries = fs::read_dir(dir_path).await?;
+/// Rejects new jobs if the working directory is over the quota or the disk is almost full.
+/// Jobs are accepted if the usage can't be calculated.
+async fn check_disk(server: &Server, size: u64) -> Result<(), (StatusCode, Json<ConvertResponse>)> {
+    if !server.disk.is_enabled() {
+        return Ok(());
+    }
 
-    // Iterate over directory entries
-    while let Some(entry) = entries.next_entry().await? {
-        let file_path = entry.path();
+    let disk = Arc::clone(&server.disk);
+    match tokio::task::spawn_blocking(move || disk.check(size)).await {
+        Ok(Ok(Err(err))) => {
+            error!("job is rejected: {}", err);
+            Err((
+                err.status(),
+                Json::from(ConvertResponse {
+                    id: None,
+                    error: Some(err.to_string()),
+                    error_code: Some(err.code()),
+                    ..Default::default()
+                }),
+            ))
+        }
+        Ok(Err(err)) => {
+            error!("couldn't check disk usage: {}", err);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
 
-        // Check if the entry is a file
-        if file_path.is_file() {
-            // Check if the file extension is ".atranscoder"
-            if let Some(extension) = file_path
-                .extension()
-                .and_then(OsStr::to_str)
-                .map(|ext| ext.to_lowercase())
+/// Sends the callback right away if the existing job is already done, otherwise it's sent
+/// when the job finishes.
+fn deduplicated_response(
+    context: &Arc<TaskContext>,
+    duplicate: Duplicate,
+) -> (StatusCode, Json<ConvertResponse>) {
+    let id = duplicate.id;
+    if let Some((callback, result)) = duplicate.done {
+        let context = Arc::clone(context);
+        tokio::task::spawn_blocking(move || {
+            let result_ttl = context.registry.result_ttl(id);
+            if let Some(delivery) =
+                context
+                    .notifier
+                    .send_ok(id, &result, result_ttl, Some(&callback))
             {
-                if extension.eq(filepath::EXT) {
-                    // Get the metadata of the file
-                    let metadata = fs::metadata(&file_path).await?;
+                log_delivery(id, &callback.url, &delivery);
+            }
+        });
+    }
+
+    (
+        StatusCode::OK,
+        Json::from(ConvertResponse {
+            id: Some(id.to_string()),
+            error: None,
+            ..Default::default()
+        }),
+    )
+}
 
-                    // Get the last modified time of the file
-                    let modified_time = metadata.modified()?;
+fn unavailable_response() -> (StatusCode, Json<ConvertResponse>) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json::from(ConvertResponse {
+            id: None,
+            error: Some("Server is shutting down".to_string()),
+            ..Default::default()
+        }),
+    )
+}
 
-                    // Calculate the duration since the last modification
-                    let duration_since_modified = now.duration_since(modified_time)?;
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Cannot install Ctrl+C handler");
+    };
 
-                    // If the file is older than one hour, remove it
-                    if duration_since_modified > Duration::from_secs(ttl) {
-                        fs::remove_file(file_path.clone()).await?;
-                        debug!("removed file: {:?}", file_path);
-                    }
-                }
-            }
-        }
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Cannot install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("shutdown signal received, stopping the server");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = "\"3e8-5f5e100\"";
+
+    fn modified() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(100_000_000)
+    }
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn parse_range_bounded() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=500-5000", 1000), Some(Ok((500, 999))));
+    }
+
+    #[test]
+    fn parse_range_open_ended() {
+        assert_eq!(parse_range("bytes=500-", 1000), Some(Ok((500, 999))));
+        assert_eq!(parse_range("bytes=0-", 1), Some(Ok((0, 0))));
+    }
+
+    #[test]
+    fn parse_range_suffix() {
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Ok((0, 999))));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+    }
+
+    #[test]
+    fn parse_range_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=1000-1001", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=-10", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn parse_range_ignored() {
+        // Multiple ranges are not supported, the whole file is sent
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=a-", 0), None);
     }
 
-    Ok(())
+    #[test]
+    fn not_modified_by_etag() {
+        let matches =
+            |value: &str| is_not_modified(&headers(header::IF_NONE_MATCH, value), ETAG, modified());
+        assert!(matches(ETAG));
+        assert!(matches(&format!("W/{}", ETAG)));
+        assert!(matches(&format!("\"other\", {}", ETAG)));
+        assert!(matches("*"));
+        assert!(!matches("\"other\""));
+    }
+
+    #[test]
+    fn not_modified_by_date() {
+        let since = |time| {
+            let value = httpdate::fmt_http_date(time);
+            is_not_modified(
+                &headers(header::IF_MODIFIED_SINCE, &value),
+                ETAG,
+                modified(),
+            )
+        };
+        assert!(since(modified()));
+        assert!(since(modified() + Duration::from_secs(1)));
+        assert!(!since(modified() - Duration::from_secs(1)));
+        assert!(!is_not_modified(&HeaderMap::new(), ETAG, modified()));
+    }
+
+    #[test]
+    fn etag_takes_precedence_over_date() {
+        let mut headers = headers(header::IF_NONE_MATCH, "\"other\"");
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_str(&httpdate::fmt_http_date(modified())).unwrap(),
+        );
+        assert!(!is_not_modified(&headers, ETAG, modified()));
+    }
+
+    #[test]
+    fn if_range() {
+        let last_modified = httpdate::fmt_http_date(modified());
+        assert!(is_range_fresh(None, ETAG, &last_modified));
+        assert!(is_range_fresh(Some(ETAG), ETAG, &last_modified));
+        assert!(is_range_fresh(Some(&last_modified), ETAG, &last_modified));
+        // Weak ETags can't be used with If-Range
+        assert!(!is_range_fresh(
+            Some(&format!("W/{}", ETAG)),
+            ETAG,
+            &last_modified
+        ));
+        assert!(!is_range_fresh(Some("\"other\""), ETAG, &last_modified));
+    }
+
+    fn api_keys() -> ApiKeys {
+        ApiKeys::load(
+            None,
+            Some(r#"[{"name": "billing", "key": "secret", "rate_limit": 1, "max_upload_size": 100}]"#),
+        )
+        .unwrap()
+    }
+
+    fn status(result: Result<Option<Arc<ApiKey>>, AuthError>) -> StatusCode {
+        result.map_or_else(|(status, _, _)| status, |_| StatusCode::OK)
+    }
+
+    #[test]
+    fn api_key_from_headers() {
+        let keys = api_keys();
+        let key = check_api_key(
+            &keys,
+            &headers(API_KEY_HEADER.parse().unwrap(), "secret"),
+            "/enqueue",
+        );
+        assert_eq!(key.unwrap().unwrap().name, "billing");
+
+        let keys = api_keys();
+        let key = check_api_key(
+            &keys,
+            &headers(header::AUTHORIZATION, "Bearer secret"),
+            "/enqueue",
+        );
+        assert_eq!(key.unwrap().unwrap().name, "billing");
+    }
+
+    #[test]
+    fn missing_or_invalid_api_key() {
+        let keys = api_keys();
+        let no_key = HeaderMap::new();
+        assert_eq!(
+            status(check_api_key(&keys, &no_key, "/enqueue")),
+            StatusCode::UNAUTHORIZED
+        );
+        let invalid = headers(header::AUTHORIZATION, "Basic secret");
+        assert_eq!(
+            status(check_api_key(&keys, &invalid, "/enqueue")),
+            StatusCode::UNAUTHORIZED
+        );
+        // Downloads may go with signed links instead of keys
+        assert!(
+            check_api_key(&keys, &no_key, &format!("/get/{}", Uuid::new_v4()))
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            check_api_key(&ApiKeys::load(None, None).unwrap(), &no_key, "/enqueue")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn api_key_limits() {
+        let keys = api_keys();
+        let mut headers = headers(API_KEY_HEADER.parse().unwrap(), "secret");
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(101));
+        assert_eq!(
+            status(check_api_key(&keys, &headers, "/enqueue")),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let (status, _, wait) = check_api_key(&keys, &headers, "/enqueue").unwrap_err();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(wait.is_some());
+    }
 }
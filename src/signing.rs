@@ -0,0 +1,133 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Calculates HMAC-SHA256 of the data.
+pub fn hmac_sha256(secret: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Calculates HMAC-SHA256 of the data and returns it as a lowercase hex string.
+pub fn hmac_sha256_hex(secret: &[u8], data: &[u8]) -> String {
+    hex::encode(hmac_sha256(secret, data))
+}
+
+/// Signs the result download links, `expires` is a Unix timestamp and `signature` is
+/// HMAC-SHA256 hex of `<method>.<job id>.<expires>`. A link is valid only for the method it
+/// was signed for, so a shared download link can't be used to delete the result.
+pub struct LinkSigner {
+    secret: Vec<u8>,
+    /// Limits the TTL of the links if set.
+    max_ttl: Option<Duration>,
+}
+
+impl LinkSigner {
+    pub(crate) fn new(secret: &str, max_ttl: Option<Duration>) -> Self {
+        LinkSigner {
+            secret: secret.as_bytes().to_vec(),
+            max_ttl,
+        }
+    }
+
+    /// Returns the query string of the link which is valid for the TTL, but no longer than
+    /// the max TTL.
+    pub fn sign(&self, method: &str, id: Uuid, ttl: Duration) -> String {
+        let ttl = self.max_ttl.map_or(ttl, |max_ttl| ttl.min(max_ttl));
+        let expires = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        format!(
+            "expires={}&signature={}",
+            expires,
+            self.signature(method, id, expires)
+        )
+    }
+
+    pub fn verify(&self, method: &str, id: Uuid, expires: u64, signature: &str) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        expires >= now
+            && constant_time_eq(
+                self.signature(method, id, expires).as_bytes(),
+                signature.to_lowercase().as_bytes(),
+            )
+    }
+
+    fn signature(&self, method: &str, id: Uuid, expires: u64) -> String {
+        hmac_sha256_hex(
+            &self.secret,
+            format!("{}.{}.{}", method, id, expires).as_bytes(),
+        )
+    }
+}
+
+/// Compares two byte strings in constant time.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Splits the signed query into `expires` and `signature`.
+    fn parse(query: &str) -> (u64, String) {
+        let (expires, signature) = query
+            .strip_prefix("expires=")
+            .and_then(|query| query.split_once("&signature="))
+            .unwrap();
+        (expires.parse().unwrap(), signature.to_string())
+    }
+
+    #[test]
+    fn signed_link_is_valid() {
+        let signer = LinkSigner::new("secret", None);
+        let id = Uuid::new_v4();
+        let (expires, signature) = parse(&signer.sign("GET", id, Duration::from_secs(600)));
+        assert!(expires >= now() + 599 && expires <= now() + 600);
+        assert!(signer.verify("GET", id, expires, &signature));
+        assert!(signer.verify("GET", id, expires, &signature.to_uppercase()));
+    }
+
+    #[test]
+    fn link_is_bound_to_method_id_and_expiry() {
+        let signer = LinkSigner::new("secret", None);
+        let id = Uuid::new_v4();
+        let (expires, signature) = parse(&signer.sign("GET", id, Duration::from_secs(600)));
+        assert!(!signer.verify("DELETE", id, expires, &signature));
+        assert!(!signer.verify("get", id, expires, &signature));
+        assert!(!signer.verify("GET", Uuid::new_v4(), expires, &signature));
+        assert!(!signer.verify("GET", id, expires + 1, &signature));
+        assert!(!LinkSigner::new("other", None).verify("GET", id, expires, &signature));
+    }
+
+    #[test]
+    fn expired_link_is_rejected() {
+        let signer = LinkSigner::new("secret", None);
+        let id = Uuid::new_v4();
+        let expires = now() - 1;
+        let signature = signer.signature("GET", id, expires);
+        assert!(!signer.verify("GET", id, expires, &signature));
+    }
+
+    #[test]
+    fn link_ttl_is_limited() {
+        let signer = LinkSigner::new("secret", Some(Duration::from_secs(60)));
+        let (expires, _) = parse(&signer.sign("GET", Uuid::new_v4(), Duration::from_secs(600)));
+        assert!(expires <= now() + 60);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use super::{ObjectMeta, Storage, StoredObject};
+use crate::filepath;
+
+/// Keeps the objects as files in a local directory. If it's the working directory,
+/// moving files in and out of the storage costs nothing. The metadata is kept in a JSON
+/// file next to the object.
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalStorage { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    /// The metadata file has the same extension as the object, so it's cleaned up with it.
+    fn meta_path(&self, key: &str) -> PathBuf {
+        let suffix = format!(".{}", filepath::EXT);
+        let name = key.strip_suffix(&suffix).unwrap_or(key);
+        self.dir.join(format!("{}.meta{}", name, suffix))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, key: &str, path: &Path, meta: &ObjectMeta) -> io::Result<()> {
+        if *meta == ObjectMeta::default() {
+            remove_if_exists(&self.meta_path(key))?;
+        } else {
+            fs::write(self.meta_path(key), serde_json::to_vec(meta)?)?;
+        }
+
+        let target = self.path(key);
+        if target == path {
+            return Ok(());
+        }
+
+        // Rename doesn't work across file systems, copy the file then.
+        if fs::rename(path, &target).is_err() {
+            fs::copy(path, &target)?;
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn fetch(&self, key: &str, path: &Path) -> io::Result<()> {
+        let source = self.path(key);
+        if source != path {
+            fs::copy(source, path)?;
+        }
+        Ok(())
+    }
+
+    fn open(&self, key: &str, offset: u64) -> io::Result<Option<StoredObject>> {
+        let mut file = match File::open(self.path(key)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let metadata = file.metadata()?;
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+
+        // Objects stored without metadata have no file
+        let meta = fs::read(self.meta_path(key))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+
+        Ok(Some(StoredObject {
+            reader: Box::new(file),
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            meta,
+        }))
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        self.path(key).try_exists()
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        remove_if_exists(&self.path(key))?;
+        remove_if_exists(&self.meta_path(key))
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn keeps_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path());
+        let key = filepath::out_file_name("job");
+        let path = dir.path().join("result");
+        fs::write(&path, b"0123456789").unwrap();
+        let meta = ObjectMeta {
+            content_type: Some("audio/ogg".to_string()),
+            file_name: Some("voice note.ogg".to_string()),
+        };
+        storage.put(&key, &path, &meta).unwrap();
+        assert!(!path.exists());
+        assert!(dir.path().join("job.out.meta.atranscoder").exists());
+
+        let mut object = storage.open(&key, 4).unwrap().unwrap();
+        assert_eq!(object.size, 10);
+        assert_eq!(object.meta, meta);
+        let mut tail = String::new();
+        object.reader.read_to_string(&mut tail).unwrap();
+        assert_eq!(tail, "456789");
+
+        assert!(storage.exists(&key).unwrap());
+        storage.delete(&key).unwrap();
+        assert!(!storage.exists(&key).unwrap());
+        assert!(storage.open(&key, 0).unwrap().is_none());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn objects_without_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path());
+        let key = filepath::in_file_name("job");
+        let path = storage.path(&key);
+        fs::write(&path, b"input").unwrap();
+        storage.put(&key, &path, &ObjectMeta::default()).unwrap();
+
+        let object = storage.open(&key, 0).unwrap().unwrap();
+        assert_eq!(object.meta, ObjectMeta::default());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,47 @@
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+mod local;
+mod s3;
+
+pub use local::LocalStorage;
+pub use s3::{S3Config, S3Storage};
+
+/// Stored object opened for reading.
+pub struct StoredObject {
+    pub reader: Box<dyn Read + Send>,
+    /// Size of the whole object, regardless of the offset it was opened at.
+    pub size: u64,
+    pub modified: SystemTime,
+    pub meta: ObjectMeta,
+}
+
+/// Kept with the object, so the results can be served with their type and name by any
+/// instance.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct ObjectMeta {
+    pub content_type: Option<String>,
+    pub file_name: Option<String>,
+}
+
+/// Where job inputs and results are kept. FFmpeg always works with local files in the
+/// working directory, the storage moves them in and out of it.
+pub trait Storage: Send + Sync {
+    /// Moves the local file into the storage under the key.
+    fn put(&self, key: &str, path: &Path, meta: &ObjectMeta) -> io::Result<()>;
+
+    /// Makes the object available as a local file at `path`.
+    fn fetch(&self, key: &str, path: &Path) -> io::Result<()>;
+
+    /// Opens the object for reading from `offset`, returns `None` if it doesn't exist.
+    fn open(&self, key: &str, offset: u64) -> io::Result<Option<StoredObject>>;
+
+    /// Checks whether the object is stored under the key.
+    fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// Removes the object, missing objects are not an error.
+    fn delete(&self, key: &str) -> io::Result<()>;
+}
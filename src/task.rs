@@ -1,89 +1,274 @@
-use crate::dto::ConvertResponse;
+use crate::avio::StreamInput;
+use crate::callback::{CallbackTarget, Notifier};
+use crate::dto::{CallbackDelivery, ErrorCode, Headers};
+use crate::fetcher::{DownloadError, Downloader};
+use crate::filepath;
+use crate::registry::{JobError, JobRegistry, JobStatus};
+use crate::storage::{ObjectMeta, Storage};
 use crate::transcoder::{Transcoder, TranscoderParams};
+use crate::uploader::Uploader;
 use ffmpeg_next::channel_layout::ChannelLayout;
 use ffmpeg_next::{format, Dictionary};
 use std::error::Error;
-use std::fs::File;
-use std::io::Write;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 use tracing::{debug, error};
-use ureq::Error as UreqError;
+
+/// Services shared by all the tasks.
+pub struct TaskContext {
+    pub registry: JobRegistry,
+    pub notifier: Notifier,
+    pub uploader: Uploader,
+    pub downloader: Arc<Downloader>,
+    pub storage: Arc<dyn Storage>,
+}
 
 #[derive(Clone)]
 pub struct Task {
     id: uuid::Uuid,
     params: TaskParams,
+    context: Arc<TaskContext>,
 }
 
 impl Task {
-    pub fn new(id: uuid::Uuid, params: TaskParams) -> Self {
-        Task { id, params }
+    pub fn new(id: uuid::Uuid, params: TaskParams, context: Arc<TaskContext>) -> Self {
+        Task {
+            id,
+            params,
+            context,
+        }
+    }
+
+    pub fn id(&self) -> uuid::Uuid {
+        self.id
+    }
+
+    pub fn params(&self) -> &TaskParams {
+        &self.params
+    }
+
+    /// Moves the input file to the storage.
+    pub fn store_input(&self) -> std::io::Result<()> {
+        self.context.storage.put(
+            &self.input_key(),
+            Path::new(&self.params.input_path),
+            &ObjectMeta::default(),
+        )
     }
 
     pub fn execute(self) -> Result<(), Box<dyn Error>> {
-        if let Some(download_url) = &self.params.url {
-            if let Err(err) = download_file(
-                download_url,
-                &self.params.input_path,
-                self.params.max_body_size,
-            ) {
-                std::fs::remove_file(Path::new(&self.params.input_path)).ok();
-                if let Err(send_err) = send_error(
-                    self.id,
-                    &format!("Couldn't download the file: {}", err),
-                    self.params.callback_url,
-                ) {
-                    eprintln!("Failed to send error callback: {}", send_err);
+        let started_at = Instant::now();
+        self.context
+            .registry
+            .set_status(self.id, JobStatus::Running);
+
+        if !self.params.stream {
+            if let Err(err) = self
+                .context
+                .storage
+                .fetch(&self.input_key(), Path::new(&self.params.input_path))
+            {
+                self.fail(
+                    ErrorCode::StorageFailed,
+                    &format!("Couldn't get the input from the storage: {}", err),
+                );
+                return Err(err.into());
+            }
+        }
+
+        let mut result = match self.clone().transcode() {
+            Ok(result) => result,
+            Err(err) => {
+                match err.downcast_ref::<DownloadError>() {
+                    Some(download_err) => self.fail(
+                        download_err.code,
+                        &format!("Couldn't download the file: {}", download_err),
+                    ),
+                    None => self.fail(
+                        ErrorCode::TranscodingFailed,
+                        &format!("Couldn't transcode: {}", err),
+                    ),
                 }
                 return Err(err);
             }
-        }
+        };
+        result.processing_time_ms = started_at.elapsed().as_millis() as u64;
 
-        if let Err(err) = self.clone().transcode() {
-            std::fs::remove_file(Path::new(&self.params.input_path)).ok();
-            std::fs::remove_file(Path::new(&self.params.output_path)).ok();
-            send_error(
-                self.id,
-                format!("Couldn't transcode: {}", err).as_str(),
-                self.params.callback_url,
-            )
-            .ok();
-            return Err(err);
+        if let Some(output_url) = &self.params.output_url {
+            if let Err(err) = self.context.uploader.upload(
+                output_url,
+                &self.params.output_path,
+                &result.mime_type,
+            ) {
+                self.fail(
+                    ErrorCode::UploadFailed,
+                    &format!("Couldn't upload the result: {}", err),
+                );
+                return Err(err);
+            }
+            debug!("job id={} result was uploaded", &self.id.to_string());
         }
 
-        std::fs::remove_file(Path::new(&self.params.input_path)).ok();
-
-        if let Err(err) = send_ok(self.id, self.params.clone().callback_url) {
-            error!(
-                "couldn't send result callback for job id={}, url {}: {}",
-                &self.id.to_string(),
-                &self.params.callback_url.unwrap_or_default(),
-                err
-            );
-        } else {
-            debug!(
-                "job id={} result was sent to callback {}",
-                &self.id.to_string(),
-                &self.params.callback_url.unwrap_or_default()
+        if let Err(err) = self.context.storage.put(
+            &self.output_key(),
+            Path::new(&self.params.output_path),
+            &ObjectMeta {
+                content_type: Some(result.mime_type.clone()),
+                file_name: Some(result.file_name.clone()),
+            },
+        ) {
+            self.fail(
+                ErrorCode::StorageFailed,
+                &format!("Couldn't store the result: {}", err),
             );
+            return Err(err.into());
+        }
+
+        self.remove_input();
+        let result = Arc::new(result);
+        self.context
+            .registry
+            .set_result(self.id, Arc::clone(&result));
+        let batches = self.context.registry.finish(self.id, None);
+        let result_ttl = self.context.registry.result_ttl(self.id);
+
+        if let Some(delivery) = self.context.notifier.send_ok(
+            self.id,
+            &result,
+            result_ttl,
+            self.params.callback.as_ref(),
+        ) {
+            self.record_delivery(delivery);
+        }
+        self.notify_duplicates(|callback| {
+            self.context
+                .notifier
+                .send_ok(self.id, &result, result_ttl, Some(callback))
+        });
+
+        for batch in &batches {
+            self.context.notifier.send_batch(batch);
         }
 
         Ok(())
     }
 
-    pub fn transcode(self) -> Result<(), Box<dyn Error>> {
+    /// Removes the job files and reports the error to the callback.
+    pub fn fail(&self, code: ErrorCode, message: &str) {
+        self.remove_input();
+        std::fs::remove_file(Path::new(&self.params.output_path)).ok();
+        if let Err(err) = self.context.storage.delete(&self.output_key()) {
+            error!("couldn't remove the result of job id={}: {}", self.id, err);
+        }
+
+        let error = JobError {
+            code,
+            message: message.to_string(),
+        };
+        let batches = self.context.registry.finish(self.id, Some(error.clone()));
+
+        if let Some(delivery) =
+            self.context
+                .notifier
+                .send_error(self.id, &error, self.params.callback.as_ref())
+        {
+            self.record_delivery(delivery);
+        }
+        self.notify_duplicates(|callback| {
+            self.context
+                .notifier
+                .send_error(self.id, &error, Some(callback))
+        });
+
+        for batch in &batches {
+            self.context.notifier.send_batch(batch);
+        }
+    }
+
+    fn input_key(&self) -> String {
+        filepath::in_file_name(&self.id.to_string())
+    }
+
+    fn output_key(&self) -> String {
+        filepath::out_file_name(&self.id.to_string())
+    }
+
+    /// Removes the input file and its stored copy.
+    pub fn remove_input(&self) {
+        std::fs::remove_file(Path::new(&self.params.input_path)).ok();
+        if let Err(err) = self.context.storage.delete(&self.input_key()) {
+            error!("couldn't remove the input of job id={}: {}", self.id, err);
+        }
+    }
+
+    fn record_delivery(&self, delivery: CallbackDelivery) {
+        let url = self
+            .params
+            .callback
+            .as_ref()
+            .map(|callback| callback.url.as_str())
+            .unwrap_or_default();
+        log_delivery(self.id, url, &delivery);
+
+        self.context
+            .registry
+            .set_callback_delivery(self.id, delivery);
+    }
+
+    /// Sends the result to the callbacks of the requests deduplicated to this job. Their
+    /// deliveries are only logged, the job status shows the delivery of its own callback.
+    fn notify_duplicates(&self, send: impl Fn(&CallbackTarget) -> Option<CallbackDelivery>) {
+        for callback in self.context.registry.take_callbacks(self.id) {
+            if let Some(delivery) = send(&callback) {
+                log_delivery(self.id, &callback.url, &delivery);
+            }
+        }
+    }
+
+    pub fn transcode(self) -> Result<JobResult, Box<dyn Error>> {
         debug!(
             "performing transcoding for task with id: {}",
             self.id.to_string()
         );
-        let mut ictx = match format::input(&self.params.input_path) {
-            Ok(val) => val,
+
+        if !self.params.stream {
+            let mut ictx = match format::input(&self.params.input_path) {
+                Ok(val) => val,
+                Err(err) => {
+                    error!("couldn't initialize input context: {:?}", err);
+                    return Err(err.into());
+                }
+            };
+            return self.transcode_input(&mut ictx);
+        }
+
+        // The input is read by FFmpeg while it's being downloaded
+        let mut reader = self.context.downloader.open(
+            self.params.url.as_deref().unwrap_or_default(),
+            self.params.headers.as_ref(),
+            self.params.max_body_size,
+        )?;
+        let result = match StreamInput::open(&mut reader) {
+            Ok(mut ictx) => self.transcode_input(&mut ictx),
             Err(err) => {
                 error!("couldn't initialize input context: {:?}", err);
-                return Err(err.into());
+                Err(err.into())
             }
         };
 
+        // FFmpeg sees an interrupted download as the end of the input, so the download
+        // error takes precedence over the transcoding result.
+        match reader.take_error() {
+            Some(err) => Err(err.into()),
+            None => result,
+        }
+    }
+
+    fn transcode_input(
+        self,
+        ictx: &mut format::context::Input,
+    ) -> Result<JobResult, Box<dyn Error>> {
         let octx = if let Some(codec_opts) = &self.params.codec_opts {
             format::output_as_with(
                 &self.params.output_path,
@@ -102,21 +287,23 @@ impl Task {
             }
         };
 
+        let channel_layout = match self.params.channel_layout.unwrap_or_default().as_str() {
+            "stereo" => ChannelLayout::STEREO,
+            "mono" => ChannelLayout::MONO,
+            "stereo_downmix" => ChannelLayout::STEREO_DOWNMIX,
+            _ => ChannelLayout::STEREO,
+        };
+
         let transcoder = Transcoder::new(
-            &mut ictx,
+            ictx,
             &mut octx,
             TranscoderParams {
-                codec: self.params.codec,
+                codec: self.params.codec.clone(),
                 codec_opts: self.params.codec_opts,
                 bit_rate: self.params.bit_rate,
                 max_bit_rate: self.params.max_bit_rate,
                 sample_rate: self.params.sample_rate,
-                channel_layout: match self.params.channel_layout.unwrap_or_default().as_str() {
-                    "stereo" => ChannelLayout::STEREO,
-                    "mono" => ChannelLayout::MONO,
-                    "stereo_downmix" => ChannelLayout::STEREO_DOWNMIX,
-                    _ => ChannelLayout::STEREO,
-                },
+                channel_layout,
             },
         );
 
@@ -190,10 +377,65 @@ impl Task {
             self.id.to_string()
         );
 
-        Ok(())
+        let size = std::fs::metadata(&self.params.output_path)?.len();
+        let duration = transcoder.duration();
+        let bit_rate = match transcoder.bit_rate() {
+            // The average is estimated from the size if the encoder doesn't report the bit rate
+            0 if duration > 0.0 => (size as f64 * 8.0 / duration) as u64,
+            bit_rate => bit_rate,
+        };
+        let muxer = octx.format();
+        let (mime_type, extension) =
+            filepath::muxer_type(muxer.name(), &muxer.mime_types(), &muxer.extensions());
+        let file_name = filepath::result_file_name(
+            self.params
+                .file_name
+                .as_deref()
+                .unwrap_or(&self.id.to_string()),
+            &extension,
+        );
+
+        Ok(JobResult {
+            size,
+            mime_type,
+            file_name,
+            duration,
+            codec: transcoder.codec_name().unwrap_or(self.params.codec),
+            sample_rate: transcoder.sample_rate(),
+            channels: channel_layout.channels() as u32,
+            bit_rate,
+            processing_time_ms: 0,
+        })
     }
 }
 
+pub fn log_delivery(id: uuid::Uuid, url: &str, delivery: &CallbackDelivery) {
+    if delivery.delivered {
+        debug!("job id={} result was sent to callback {}", id, url);
+    } else {
+        error!(
+            "couldn't send callback for job id={}, url {} after {} attempts: {}",
+            id,
+            url,
+            delivery.attempts,
+            delivery.error.as_deref().unwrap_or_default()
+        );
+    }
+}
+
+/// Information about the transcoding result which is sent to the callback.
+pub struct JobResult {
+    pub size: u64,
+    pub mime_type: String,
+    pub file_name: String,
+    pub duration: f64,
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bit_rate: u64,
+    pub processing_time_ms: u64,
+}
+
 #[derive(Clone)]
 pub struct TaskParams {
     pub format: String,
@@ -204,103 +446,18 @@ pub struct TaskParams {
     pub sample_rate: i32,
     pub channel_layout: Option<String>,
     pub url: Option<String>,
+    pub headers: Option<Headers>,
+    /// Expected SHA-256 hex of the downloaded input.
+    pub checksum: Option<String>,
+    /// Transcode the URL input while it's being downloaded, without saving it first.
+    pub stream: bool,
     pub input_path: String,
     pub output_path: String,
-    pub callback_url: Option<String>,
+    pub callback: Option<CallbackTarget>,
+    pub output_url: Option<String>,
     pub max_body_size: usize,
-}
-
-fn download_file(url: &str, output_path: &str, max_size: usize) -> Result<(), Box<dyn Error>> {
-    let response = ureq::get(url).call();
-
-    match response {
-        Ok(response) => {
-            if response.status() != 200 {
-                return Err(format!("Failed to download file: HTTP {}", response.status()).into());
-            }
-
-            let mut reader = response.into_reader();
-            let mut file = File::create(output_path)?;
-            let mut buffer = vec![0; 8 * 1024]; // Read in 8KB chunks
-            let mut total_size = 0;
-
-            loop {
-                let bytes_read = reader.read(&mut buffer)?;
-                if bytes_read == 0 {
-                    break;
-                }
-
-                total_size += bytes_read;
-                if total_size > max_size {
-                    return Err("Response body exceeds the limit".into());
-                }
-
-                file.write_all(&buffer[..bytes_read])?;
-            }
-        }
-        Err(UreqError::Status(code, _response)) => {
-            return Err(format!("Failed to download file: HTTP {}", code).into());
-        }
-        Err(e) => {
-            return Err(format!("Failed to make request: {}", e).into());
-        }
-    }
-
-    Ok(())
-}
-
-fn send_error(
-    id: uuid::Uuid,
-    error: &str,
-    maybe_url: Option<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let url = maybe_url.unwrap_or_default();
-    if url.is_empty() {
-        return Ok(());
-    }
-
-    let response = ureq::post(url.as_str())
-        .set("Content-Type", "application/json")
-        .send_json(ConvertResponse {
-            id: Some(id.to_string()),
-            error: Some(error.to_string()),
-        })?;
-
-    if response.status() == 200 {
-        Ok(())
-    } else {
-        Err(format!(
-            "failed to send callback to {}. Status: {}",
-            url,
-            response.status()
-        )
-        .into())
-    }
-}
-
-fn send_ok(id: uuid::Uuid, maybe_url: Option<String>) -> Result<(), Box<dyn Error>> {
-    let url = maybe_url.unwrap_or_default();
-    if url.is_empty() {
-        return Ok(());
-    }
-
-    let response = ureq::post(url.as_str())
-        .set("Content-Type", "application/json")
-        .send_json(ConvertResponse {
-            id: Some(id.to_string()),
-            error: None,
-        })?;
-
-    if response.status() == 200 {
-        Ok(())
-    } else {
-        Err(format!(
-            "failed to send callback to {}. Status: {}",
-            url,
-            response.status()
-        )
-        .into())
-    }
+    /// Name of the result file, the extension of the output format is added if it has none.
+    pub file_name: Option<String>,
 }
 
 pub fn params_to_avdictionary(input: &str) -> Dictionary {
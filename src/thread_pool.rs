@@ -1,16 +1,25 @@
 use ffmpeg_next::log::Level;
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SendError, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 
+use crate::dto::ErrorCode;
 use crate::task::Task;
 
+pub(crate) const SHUTDOWN_MESSAGE: &str = "Server is shutting down";
+const SUPERVISOR_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Sender<Task>,
+    workers: Arc<Mutex<Vec<Worker>>>,
+    sender: Mutex<Option<Sender<Task>>>,
+    receiver: Arc<Mutex<Receiver<Task>>>,
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl ThreadPool {
@@ -18,28 +27,125 @@ impl ThreadPool {
         let num_threads = num_threads.unwrap_or_else(num_cpus::get);
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let shutting_down = Arc::new(AtomicBool::new(false));
 
         let workers = (0..num_threads)
-            .map(|id| Worker::new(id, Arc::clone(&receiver)))
+            .map(|id| Worker::new(id, Arc::clone(&receiver), Arc::clone(&shutting_down)))
             .collect();
 
-        ThreadPool { workers, sender }
+        let pool = ThreadPool {
+            workers: Arc::new(Mutex::new(workers)),
+            sender: Mutex::new(Some(sender)),
+            receiver,
+            shutting_down,
+        };
+        pool.start_supervisor();
+        pool
+    }
+
+    /// Replaces dead workers with the new ones so the pool always keeps its capacity.
+    fn start_supervisor(&self) {
+        let workers = Arc::clone(&self.workers);
+        let receiver = Arc::clone(&self.receiver);
+        let shutting_down = Arc::clone(&self.shutting_down);
+
+        thread::spawn(move || loop {
+            thread::sleep(SUPERVISOR_INTERVAL);
+            if shutting_down.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let mut workers = workers.lock().unwrap_or_else(PoisonError::into_inner);
+            for worker in workers.iter_mut() {
+                if !worker.is_dead() {
+                    continue;
+                }
+
+                if let Some(Err(err)) = worker.thread.take().map(|thread| thread.join()) {
+                    error!("worker {} died: {}", worker.id, panic_message(&*err));
+                }
+
+                if let Some(task) = worker.take_current() {
+                    task.fail(
+                        ErrorCode::WorkerCrashed,
+                        "Worker has crashed while processing the job",
+                    );
+                }
+
+                warn!("respawning worker {}", worker.id);
+                *worker = Worker::new(worker.id, Arc::clone(&receiver), Arc::clone(&shutting_down));
+            }
+        });
+    }
+
+    /// Sends the task to the queue. The task is returned back inside the error
+    /// if the pool doesn't accept new tasks anymore.
+    pub fn enqueue(&self, task: Task) -> Result<(), Box<SendError<Task>>> {
+        match self.sender.lock().unwrap().as_ref() {
+            Some(sender) => sender.send(task).map_err(Box::new),
+            None => Err(Box::new(SendError(task))),
+        }
     }
 
-    pub fn enqueue(&self, task: Task) {
-        if let Err(e) = self.sender.send(task) {
-            error!("failed to send task to the queue: {:?}", e);
+    /// Stops accepting new tasks, fails the queued ones and waits up to `grace_period`
+    /// for the running tasks to finish. Tasks that are still running after that are
+    /// reported as failed to their callbacks.
+    pub fn shutdown(&self, grace_period: Duration) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        drop(self.sender.lock().unwrap().take());
+
+        // Workers release the receiver right away once the channel is closed.
+        let receiver = self.receiver.lock().unwrap_or_else(PoisonError::into_inner);
+        for task in receiver.try_iter() {
+            task.fail(ErrorCode::ShuttingDown, SHUTDOWN_MESSAGE);
+        }
+        drop(receiver);
+
+        let deadline = Instant::now() + grace_period;
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        for worker in workers.iter_mut() {
+            let Some(thread) = worker.thread.take() else {
+                continue;
+            };
+
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            if thread.is_finished() {
+                if thread.join().is_err() {
+                    error!("worker {} panicked", worker.id);
+                }
+                continue;
+            }
+
+            if let Some(task) = worker.take_current() {
+                warn!(
+                    "worker {} didn't finish the task within the grace period",
+                    worker.id
+                );
+                task.fail(ErrorCode::ShuttingDown, SHUTDOWN_MESSAGE);
+            }
         }
+
+        info!("thread pool has been stopped");
     }
 }
 
 struct Worker {
     id: usize,
+    current: Arc<Mutex<Option<Task>>>,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Task>>>) -> Self {
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<Receiver<Task>>>,
+        shutting_down: Arc<AtomicBool>,
+    ) -> Self {
+        let current = Arc::new(Mutex::new(None));
+        let current_task = Arc::clone(&current);
         let thread = thread::spawn(move || {
             ffmpeg_next::init()
                 .unwrap_or_else(|err| tracing::error!("couldn't init FFmpeg: {:?}", err));
@@ -54,20 +160,50 @@ impl Worker {
 
             loop {
                 let task = {
-                    let lock = receiver.lock().unwrap();
+                    let lock = receiver.lock().unwrap_or_else(PoisonError::into_inner);
                     lock.recv()
                 };
 
                 match task {
                     Ok(task) => {
+                        if shutting_down.load(Ordering::SeqCst) {
+                            task.fail(ErrorCode::ShuttingDown, SHUTDOWN_MESSAGE);
+                            continue;
+                        }
+
                         debug!("worker {} got a task; executing.", id);
-                        if let Err(err) = task.execute() {
-                            error!("worker {} failed to finish the task: {:?}", id, err);
+                        *current_task.lock().unwrap_or_else(PoisonError::into_inner) =
+                            Some(task.clone());
+
+                        match panic::catch_unwind(AssertUnwindSafe(|| task.execute())) {
+                            Ok(Ok(())) => {}
+                            Ok(Err(err)) => {
+                                error!("worker {} failed to finish the task: {:?}", id, err)
+                            }
+                            Err(err) => {
+                                let msg = panic_message(&*err);
+                                error!("worker {} panicked while executing the task: {}", id, msg);
+                                let task = current_task
+                                    .lock()
+                                    .unwrap_or_else(PoisonError::into_inner)
+                                    .take();
+                                if let Some(task) = task {
+                                    task.fail(
+                                        ErrorCode::WorkerCrashed,
+                                        &format!("Couldn't transcode: panic: {}", msg),
+                                    );
+                                }
+                            }
                         }
+
+                        current_task
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .take();
                     }
-                    Err(e) => {
-                        error!("worker {} failed to receive task: {:?}", id, e);
-                        thread::sleep(Duration::from_secs(1)); // sleep to avoid busy-looping
+                    Err(_) => {
+                        debug!("worker {} stopped: the queue is closed", id);
+                        break;
                     }
                 }
             }
@@ -75,7 +211,32 @@ impl Worker {
 
         Worker {
             id,
+            current,
             thread: Some(thread),
         }
     }
+
+    fn is_dead(&self) -> bool {
+        match &self.thread {
+            Some(thread) => thread.is_finished(),
+            None => true,
+        }
+    }
+
+    fn take_current(&self) -> Option<Task> {
+        self.current
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
 }
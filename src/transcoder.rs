@@ -14,6 +14,7 @@ pub struct Transcoder {
     encoder: codec::encoder::Audio,
     pub(crate) in_time_base: ffmpeg::Rational,
     out_time_base: ffmpeg::Rational,
+    samples: u64,
 }
 
 pub struct TranscoderParams {
@@ -122,9 +123,33 @@ impl Transcoder {
             encoder,
             in_time_base,
             out_time_base: output.time_base(),
+            samples: 0,
         })
     }
 
+    pub(crate) fn codec_name(&self) -> Option<String> {
+        self.encoder.codec().map(|codec| codec.name().to_string())
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.encoder.rate()
+    }
+
+    /// Bit rate the encoder was opened with, zero if it's not set (e.g. for lossless codecs).
+    pub(crate) fn bit_rate(&self) -> u64 {
+        // ffmpeg-next has no getter for the encoder's bit rate
+        let bit_rate = unsafe { (*self.encoder.as_ptr()).bit_rate };
+        u64::try_from(bit_rate).unwrap_or_default()
+    }
+
+    /// Duration of the encoded audio in seconds.
+    pub(crate) fn duration(&self) -> f64 {
+        match self.encoder.rate() {
+            0 => 0.0,
+            rate => self.samples as f64 / rate as f64,
+        }
+    }
+
     fn send_frame_to_encoder(&mut self, frame: &ffmpeg::Frame) -> Result<(), ffmpeg::Error> {
         self.encoder.send_frame(frame)
     }
@@ -186,6 +211,7 @@ impl Transcoder {
             }
 
             self.send_frame_to_encoder(&filtered)?;
+            self.samples += filtered.samples() as u64;
             self.receive_and_process_encoded_packets(octx)?;
         }
     }
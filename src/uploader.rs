@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fs::File;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, warn};
+use ureq::{Agent, AgentBuilder};
+use url::Url;
+
+use crate::fetcher::{redacted_url, DownloadOptions};
+use crate::url_policy::UrlPolicy;
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Uploads transcoding results to the caller-supplied URLs (e.g. presigned S3 PUT URLs).
+/// Network errors, 429 and 5xx responses are retried up to `max_attempts` times with
+/// exponential backoff. The URLs are checked against the same policy as the downloads.
+pub struct Uploader {
+    agent: Agent,
+    policy: Arc<UrlPolicy>,
+    max_attempts: u32,
+}
+
+impl Uploader {
+    pub(crate) fn new(
+        max_attempts: u32,
+        policy: Arc<UrlPolicy>,
+        options: &DownloadOptions,
+    ) -> Self {
+        let resolver_policy = Arc::clone(&policy);
+        // Redirects are not followed, the body can't be sent again to an unchecked URL.
+        let agent = AgentBuilder::new()
+            .timeout_connect(options.connect_timeout)
+            .timeout_read(options.read_timeout)
+            .timeout_write(options.read_timeout)
+            .redirects(0)
+            .resolver(move |netloc: &str| resolver_policy.resolve(netloc))
+            .build();
+
+        Uploader {
+            agent,
+            policy,
+            max_attempts: max_attempts.max(1),
+        }
+    }
+
+    pub fn check_url(&self, url: &str) -> Result<Url, String> {
+        self.policy.check_url(url)
+    }
+
+    pub fn upload(&self, url: &str, path: &str, mime_type: &str) -> Result<(), Box<dyn Error>> {
+        let url = self.check_url(url)?;
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            // The file is reopened for every attempt, so the body is always sent from the start.
+            let file = File::open(path)?;
+            let size = file.metadata()?.len();
+
+            // Explicit Content-Length disables chunked encoding, which presigned URLs don't support.
+            let response = self
+                .agent
+                .put(url.as_str())
+                .set("Content-Type", mime_type)
+                .set("Content-Length", &size.to_string())
+                .send(file);
+
+            let (err, retryable) = match response {
+                Ok(response) if response.status() >= 300 => {
+                    (format!("upload failed: HTTP {}", response.status()), false)
+                }
+                Ok(response) => {
+                    debug!("result was uploaded: HTTP {}", response.status());
+                    return Ok(());
+                }
+                Err(ureq::Error::Status(status, _)) => (
+                    format!("upload failed: HTTP {}", status),
+                    status == 429 || status >= 500,
+                ),
+                // The error contains the URL, which may be presigned
+                Err(ureq::Error::Transport(err)) => (
+                    format!("upload to {} failed: {}", redacted_url(&url), err.kind()),
+                    true,
+                ),
+            };
+
+            if !retryable || attempt >= self.max_attempts {
+                return Err(err.into());
+            }
+
+            warn!("{} (attempt {}), retrying in {:?}", err, attempt, delay);
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::url_policy::DEFAULT_SCHEMES;
+
+    #[test]
+    fn errors_have_no_query() {
+        // Nothing listens on the port, so the connection is refused
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let policy = UrlPolicy::new(DEFAULT_SCHEMES, "", "", "", "").unwrap();
+        let options = DownloadOptions {
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            max_redirects: 0,
+            max_resumes: 0,
+        };
+        let uploader = Uploader::new(1, Arc::new(policy), &options);
+        let file = tempfile::NamedTempFile::new().unwrap();
+
+        let err = uploader
+            .upload(
+                &format!("http://127.0.0.1:{}/out.ogg?X-Amz-Signature=secret", port),
+                file.path().to_str().unwrap(),
+                "audio/ogg",
+            )
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("/out.ogg"), "{}", err);
+        assert!(!err.contains("secret"), "{}", err);
+    }
+}
@@ -0,0 +1,319 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+use url::{Host, Url};
+
+pub const DEFAULT_SCHEMES: &str = "http,https";
+/// Loopback, private, link-local, shared, multicast and reserved ranges. IPv6 ranges which
+/// embed IPv4 addresses (IPv4-compatible `::a.b.c.d` and NAT64) are denied as a whole.
+pub const DEFAULT_DENIED_CIDRS: &str = "0.0.0.0/8,10.0.0.0/8,100.64.0.0/10,127.0.0.0/8,\
+    169.254.0.0/16,172.16.0.0/12,192.0.0.0/24,192.168.0.0/16,198.18.0.0/15,224.0.0.0/4,\
+    240.0.0.0/4,::/96,64:ff9b::/96,64:ff9b:1::/48,fc00::/7,fe80::/10,ff00::/8";
+
+struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(value: &str) -> Result<Self, String> {
+        let (addr, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid CIDR: {}", value))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max_prefix
+        } else {
+            prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid CIDR: {}", value))?
+        };
+        Ok(Cidr { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Decides which remote URLs may be fetched. Denied hosts and CIDRs always win, if the allow
+/// lists are not empty only the hosts and addresses matching them are allowed.
+///
+/// Host patterns are either exact host names or `*.example.com` to match all the subdomains.
+pub struct UrlPolicy {
+    schemes: Vec<String>,
+    allowed_hosts: Vec<String>,
+    denied_hosts: Vec<String>,
+    allowed_cidrs: Vec<Cidr>,
+    denied_cidrs: Vec<Cidr>,
+}
+
+impl UrlPolicy {
+    /// Every argument is a comma-separated list.
+    pub(crate) fn new(
+        schemes: &str,
+        allowed_hosts: &str,
+        denied_hosts: &str,
+        allowed_cidrs: &str,
+        denied_cidrs: &str,
+    ) -> Result<Self, String> {
+        Ok(UrlPolicy {
+            schemes: split_list(schemes),
+            allowed_hosts: split_list(allowed_hosts),
+            denied_hosts: split_list(denied_hosts),
+            allowed_cidrs: parse_cidrs(allowed_cidrs)?,
+            denied_cidrs: parse_cidrs(denied_cidrs)?,
+        })
+    }
+
+    /// Checks the URL before connecting to it. Host names are checked again when they are
+    /// resolved, see [`UrlPolicy::resolve`].
+    pub fn check_url(&self, url: &str) -> Result<Url, String> {
+        let url = Url::parse(url).map_err(|err| format!("invalid URL: {}", err))?;
+
+        if !self.schemes.iter().any(|scheme| scheme == url.scheme()) {
+            return Err(format!("URL scheme {} is not allowed", url.scheme()));
+        }
+
+        match url.host() {
+            Some(Host::Domain(host)) => self.check_host(host)?,
+            Some(Host::Ipv4(ip)) => {
+                self.check_host(&ip.to_string())?;
+                self.check_ip(IpAddr::V4(ip))?;
+            }
+            Some(Host::Ipv6(ip)) => {
+                self.check_host(&ip.to_string())?;
+                self.check_ip(IpAddr::V6(ip))?;
+            }
+            None => return Err("URL must contain a host".to_string()),
+        }
+
+        Ok(url)
+    }
+
+    /// IPv6 addresses which embed an IPv4 address are checked in both forms, so they can't
+    /// bypass the IPv4 ranges. They are allowed if either form is in the allowed ranges.
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), String> {
+        let embedded = match ip {
+            IpAddr::V6(v6) => embedded_ipv4(v6).map(IpAddr::V4),
+            IpAddr::V4(_) => None,
+        };
+        let forms = || std::iter::once(ip).chain(embedded);
+
+        if forms().any(|ip| self.denied_cidrs.iter().any(|cidr| cidr.contains(ip)))
+            || (!self.allowed_cidrs.is_empty()
+                && !forms().any(|ip| self.allowed_cidrs.iter().any(|cidr| cidr.contains(ip))))
+        {
+            return Err(format!("address {} is not allowed", ip));
+        }
+        Ok(())
+    }
+
+    /// Resolves `host:port` and keeps only the allowed addresses. Used as the HTTP client
+    /// resolver, so every connection, including the ones after redirects, is checked.
+    pub fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+        let allowed: Vec<SocketAddr> = addrs
+            .iter()
+            .filter(|addr| self.check_ip(addr.ip()).is_ok())
+            .copied()
+            .collect();
+
+        if allowed.is_empty() && !addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} resolves to addresses which are not allowed", netloc),
+            ));
+        }
+        Ok(allowed)
+    }
+
+    fn check_host(&self, host: &str) -> Result<(), String> {
+        let host = host.trim_end_matches('.').to_lowercase();
+        if self
+            .denied_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, &host))
+            || (!self.allowed_hosts.is_empty()
+                && !self
+                    .allowed_hosts
+                    .iter()
+                    .any(|pattern| host_matches(pattern, &host)))
+        {
+            return Err(format!("host {} is not allowed", host));
+        }
+        Ok(())
+    }
+}
+
+/// IPv4 address embedded into IPv4-mapped (`::ffff:a.b.c.d`), IPv4-compatible (`::a.b.c.d`)
+/// or NAT64 (`64:ff9b::a.b.c.d`) IPv6 address.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return Some(v4);
+    }
+    let bits = u128::from(ip);
+    let prefix = bits >> 32;
+    // `::` and `::1` are not IPv4-compatible addresses.
+    let compatible = prefix == 0 && bits > 1;
+    let nat64 = prefix == 0x0064_ff9b_0000_0000_0000_0000;
+    (compatible || nat64).then(|| Ipv4Addr::from(bits as u32))
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().trim_end_matches('.').to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn parse_cidrs(value: &str) -> Result<Vec<Cidr>, String> {
+    split_list(value)
+        .iter()
+        .map(|cidr| Cidr::parse(cidr))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn default_policy() -> UrlPolicy {
+        UrlPolicy::new(DEFAULT_SCHEMES, "", "", "", DEFAULT_DENIED_CIDRS).unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains(ip("10.255.0.1")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        // Families never match each other
+        assert!(!net.contains(ip("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn cidr_zero_prefix_matches_everything() {
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("203.0.113.7")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn cidr_full_prefix_matches_one_address() {
+        let v4 = Cidr::parse("192.0.2.1/32").unwrap();
+        assert!(v4.contains(ip("192.0.2.1")));
+        assert!(!v4.contains(ip("192.0.2.2")));
+
+        let v6 = Cidr::parse("2001:db8::1/128").unwrap();
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("2001:db8::2")));
+
+        // No prefix is the same as the full one
+        assert!(Cidr::parse("192.0.2.1").unwrap().contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn cidr_invalid() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("::/129").is_err());
+        assert!(Cidr::parse("example.com/8").is_err());
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(host_matches("example.com", "example.com"));
+        assert!(!host_matches("example.com", "cdn.example.com"));
+        assert!(host_matches("*.example.com", "cdn.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn default_ranges() {
+        let policy = default_policy();
+        for denied in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "169.254.169.254",
+            "::",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(policy.check_ip(ip(denied)).is_err(), "{}", denied);
+        }
+        assert!(policy.check_ip(ip("93.184.216.34")).is_ok());
+        assert!(policy.check_ip(ip("2606:2800:220:1::1")).is_ok());
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let policy = default_policy();
+        assert!(policy.check_ip(ip("::ffff:127.0.0.1")).is_err());
+        assert!(policy.check_ip(ip("::ffff:169.254.169.254")).is_err());
+        assert!(policy.check_ip(ip("::ffff:93.184.216.34")).is_ok());
+    }
+
+    #[test]
+    fn ipv4_embedding_ranges() {
+        let policy = default_policy();
+        assert!(policy.check_ip(ip("::127.0.0.1")).is_err());
+        assert!(policy.check_ip(ip("64:ff9b::7f00:1")).is_err());
+        assert!(policy.check_ip(ip("64:ff9b:1::a00:1")).is_err());
+
+        // Without the default ranges the embedded address is still checked
+        let policy = UrlPolicy::new(DEFAULT_SCHEMES, "", "", "", "127.0.0.0/8").unwrap();
+        assert!(policy.check_ip(ip("::127.0.0.1")).is_err());
+        assert!(policy.check_ip(ip("64:ff9b::127.0.0.1")).is_err());
+        assert!(policy.check_ip(ip("64:ff9b::93.184.216.34")).is_ok());
+    }
+
+    #[test]
+    fn allowed_ranges() {
+        let policy = UrlPolicy::new(DEFAULT_SCHEMES, "", "", "93.184.216.0/24", "").unwrap();
+        assert!(policy.check_ip(ip("93.184.216.34")).is_ok());
+        assert!(policy.check_ip(ip("::ffff:93.184.216.34")).is_ok());
+        assert!(policy.check_ip(ip("93.184.217.1")).is_err());
+    }
+
+    #[test]
+    fn check_url() {
+        let policy =
+            UrlPolicy::new(DEFAULT_SCHEMES, "", "*.internal", "", DEFAULT_DENIED_CIDRS).unwrap();
+        assert!(policy.check_url("https://example.com/a.mp3").is_ok());
+        assert!(policy.check_url("ftp://example.com/a.mp3").is_err());
+        assert!(policy.check_url("http://127.0.0.1/a.mp3").is_err());
+        assert!(policy.check_url("http://[::ffff:7f00:1]/a.mp3").is_err());
+        assert!(policy.check_url("http://db.internal./a.mp3").is_err());
+        assert!(policy.check_url("http://DB.Internal/a.mp3").is_err());
+    }
+}